@@ -0,0 +1,965 @@
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_yaml_ng::{Mapping, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::diagnostics::{self, Diagnostic, Position, Segment, SourceMap};
+use crate::interpolation::{self, InterpolationError, Variables};
+use crate::servers::{self, Server, ServerConfigError};
+use crate::wol;
+
+/// A problem with a config document, and the path to the value it is about
+type Problem = (ServerConfigError, Vec<Segment>);
+
+type Result<T> = std::result::Result<T, Problem>;
+
+/// Languages a config file can be written in, all describing the same servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Pick the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Format> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    /// Parse a whole file into a YAML value, so that every format goes through the same
+    /// defaults, deserialization and validation.
+    ///
+    /// Syntax errors come with the position the parser stopped at.
+    fn parse(self, content: &str) -> std::result::Result<Value, (String, Option<Position>)> {
+        // The position is shown with the snippet, so it is left out of the message
+        let at = |message: String, line, column| {
+            let suffix = format!(" at line {} column {}", line, column);
+            let message = message
+                .strip_suffix(&suffix)
+                .unwrap_or(&message)
+                .to_string();
+            let position = Position {
+                line,
+                column,
+                length: 1,
+            };
+            (message, Some(position))
+        };
+        match self {
+            Format::Yaml => serde_yaml_ng::from_str(content).map_err(|e| match e.location() {
+                Some(location) => at(e.to_string(), location.line(), location.column()),
+                None => (e.to_string(), None),
+            }),
+            Format::Toml => toml::from_str(content).map_err(|e| {
+                let position = e
+                    .span()
+                    .map(|span| Position::from_offset(content, span.start, span.len().max(1)));
+                (e.message().to_string(), position)
+            }),
+            Format::Json => {
+                serde_json::from_str(content).map_err(|e| at(e.to_string(), e.line(), e.column()))
+            }
+        }
+    }
+
+    /// Positions of the values in the file, TOML files are only located by their syntax errors
+    fn source_map(self, content: &str) -> SourceMap {
+        match self {
+            Format::Yaml | Format::Json => SourceMap::yaml(content),
+            Format::Toml => SourceMap::new(content),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("{} is not a config format", s)),
+        }
+    }
+}
+
+/// Where a server was defined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub file: PathBuf,
+    /// Path to the server in the file
+    pub path: Vec<Segment>,
+}
+
+/// Checks that a value of the `defaults` section is valid for its field
+type DefaultCheck =
+    fn(&Value) -> std::result::Result<(), serde_path_to_error::Error<serde_yaml_ng::Error>>;
+
+/// Server fields that can be given a default value in the `defaults` section
+const SERVER_DEFAULTS: [(&str, DefaultCheck); 3] = [
+    ("interface", check_default::<String>),
+    ("vlan", check_default::<wol::Vlan>),
+    ("transport", check_default::<wol::Transport>),
+];
+/// Health check fields that can be given a default value in the `defaults` section
+const CHECK_DEFAULTS: [(&str, DefaultCheck); 2] = [
+    ("retry", check_default::<humantime_serde::Serde<Duration>>),
+    ("timeout", check_default::<humantime_serde::Serde<Duration>>),
+];
+/// Server fields holding lists of health checks
+const CHECK_LISTS: [&str; 2] = ["check", "down_check"];
+
+/// A config file with global defaults, instead of a bare list of servers
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    #[serde(default)]
+    defaults: Mapping,
+    /// Other config files, or directories of config files, to read servers from
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    servers: Vec<Value>,
+}
+
+/// Deserialize a value of the `defaults` section, only to check that it is valid for its field
+fn check_default<T: DeserializeOwned>(
+    value: &Value,
+) -> std::result::Result<(), serde_path_to_error::Error<serde_yaml_ng::Error>> {
+    serde_path_to_error::deserialize::<_, T>(value.clone()).map(drop)
+}
+
+/// Check the settings applied to every server, and every health check, that does not set them
+/// itself.
+///
+/// The defaults are kept as YAML values and merged into the servers before they are deserialized,
+/// so that a value set on a server always wins, even when it is the built-in default.
+fn validate_defaults(defaults: &Mapping) -> Result<()> {
+    for (key, value) in defaults {
+        let field = key.as_str().unwrap_or_default();
+        let fields = SERVER_DEFAULTS.iter().chain(&CHECK_DEFAULTS);
+        let Some((_, check)) = fields.clone().find(|(name, _)| *name == field) else {
+            let expected: Vec<String> = fields.map(|(name, _)| format!("`{}`", name)).collect();
+            let message = format!(
+                "defaults: unknown field `{}`, expected one of {}",
+                field,
+                expected.join(", ")
+            );
+            let path = vec![Segment::key("defaults"), key_segment(key)];
+            return Err((ServerConfigError::ParseError(message), path));
+        };
+        check(value).map_err(|e| {
+            let mut path = vec![Segment::key("defaults"), key_segment(key)];
+            path.extend(diagnostics::serde_path(e.path()));
+            let message = format!("defaults: {}: {}", field, e.into_inner());
+            (ServerConfigError::ParseError(message), path)
+        })?;
+    }
+    Ok(())
+}
+
+/// Insert every `keys` entry of `defaults` that `target` does not set
+fn merge_defaults(target: &mut Value, defaults: &Mapping, keys: &[(&str, DefaultCheck)]) {
+    let Some(target) = target.as_mapping_mut() else {
+        return;
+    };
+    for (key, _) in keys {
+        if let Some(value) = defaults.get(*key) {
+            if !target.contains_key(*key) {
+                target.insert(Value::from(*key), value.clone());
+            }
+        }
+    }
+}
+
+fn apply_defaults(server: &mut Value, defaults: &Mapping) {
+    merge_defaults(server, defaults, &SERVER_DEFAULTS);
+
+    for list in CHECK_LISTS {
+        if let Some(Value::Sequence(checks)) = server.get_mut(list) {
+            for check in checks {
+                merge_defaults(check, defaults, &CHECK_DEFAULTS);
+            }
+        }
+    }
+}
+
+/// How to refer to a server in errors, before it is known to be valid
+fn describe_server(server: &Value, index: usize) -> String {
+    match server.get("name").and_then(Value::as_str) {
+        Some(name) => name.to_string(),
+        None => format!("#{}", index + 1),
+    }
+}
+
+/// The tags of a server, before it is known to be valid
+fn server_tags(server: &Value) -> Vec<String> {
+    match server.get("tags") {
+        Some(Value::Sequence(tags)) => tags
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn key_segment(key: &Value) -> Segment {
+    Segment::key(key.as_str().unwrap_or_default())
+}
+
+/// Interpolate every string in `value`, including the ones nested in maps and lists, returning
+/// the path to the string that could not be interpolated
+fn interpolate_value(
+    value: &mut Value,
+    dir: &Path,
+    variables: &Variables,
+    secrets: &mut Vec<String>,
+    path: &mut Vec<Segment>,
+) -> std::result::Result<(), (InterpolationError, Vec<Segment>)> {
+    match value {
+        Value::String(text) => {
+            *text = interpolation::interpolate(text, dir, variables, secrets)
+                .map_err(|e| (e, path.clone()))?
+        }
+        Value::Sequence(values) => {
+            for (index, value) in values.iter_mut().enumerate() {
+                path.push(Segment::Index(index));
+                interpolate_value(value, dir, variables, secrets, path)?;
+                path.pop();
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                path.push(key_segment(key));
+                interpolate_value(value, dir, variables, secrets, path)?;
+                path.pop();
+            }
+        }
+        Value::Tagged(tagged) => {
+            interpolate_value(&mut tagged.value, dir, variables, secrets, path)?
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Secrets interpolated into a health check, by check list and position in the list
+type CheckSecrets = Vec<(String, usize, Vec<String>)>;
+
+/// Interpolate the fields of a server, keeping track of the secrets used in each health check,
+/// and of the ones used in the other fields
+fn interpolate_server(
+    server: &mut Value,
+    description: &str,
+    dir: &Path,
+    variables: &Variables,
+) -> Result<(CheckSecrets, Vec<String>)> {
+    let mut check_secrets = Vec::new();
+    let mut field_secrets = Vec::new();
+    let Some(fields) = server.as_mapping_mut() else {
+        return Ok((check_secrets, field_secrets));
+    };
+
+    for (key, value) in fields.iter_mut() {
+        let key = key.as_str().unwrap_or_default().to_string();
+        let mut path = vec![Segment::key(&key)];
+        match value {
+            Value::Sequence(checks) if CHECK_LISTS.contains(&key.as_str()) => {
+                for (index, check) in checks.iter_mut().enumerate() {
+                    let mut secrets = Vec::new();
+                    path.push(Segment::Index(index));
+                    interpolate_value(check, dir, variables, &mut secrets, &mut path).map_err(
+                        |(e, path)| {
+                            let location =
+                                format!("server {}, {} #{}", description, key, index + 1);
+                            (ServerConfigError::BadInterpolation(location, e), path)
+                        },
+                    )?;
+                    path.pop();
+                    check_secrets.push((key.clone(), index, secrets));
+                }
+            }
+            _ => interpolate_value(value, dir, variables, &mut field_secrets, &mut path).map_err(
+                |(e, path)| {
+                    let location = format!("server {}, {}", description, key);
+                    (ServerConfigError::BadInterpolation(location, e), path)
+                },
+            )?,
+        }
+    }
+
+    Ok((check_secrets, field_secrets))
+}
+
+/// Read a config document, either a bare list of servers or a map with `defaults`, `include`
+/// and `servers`, along with the path to the list of servers
+fn parse_document(
+    document: Value,
+    dir: &Path,
+    variables: &Variables,
+) -> Result<(Document, Vec<Segment>)> {
+    match document {
+        Value::Sequence(servers) => Ok((
+            Document {
+                servers,
+                ..Default::default()
+            },
+            Vec::new(),
+        )),
+        Value::Mapping(_) => {
+            let mut document: Document =
+                serde_path_to_error::deserialize(document).map_err(|e| {
+                    let path = diagnostics::serde_path(e.path());
+                    (
+                        ServerConfigError::ParseError(e.into_inner().to_string()),
+                        path,
+                    )
+                })?;
+
+            for (key, value) in document.defaults.iter_mut() {
+                let mut path = vec![Segment::key("defaults"), key_segment(key)];
+                interpolate_value(value, dir, variables, &mut Vec::new(), &mut path).map_err(
+                    |(e, path)| {
+                        let location = format!("defaults, {}", key.as_str().unwrap_or_default());
+                        (ServerConfigError::BadInterpolation(location, e), path)
+                    },
+                )?;
+            }
+            validate_defaults(&document.defaults)?;
+
+            Ok((document, vec![Segment::key("servers")]))
+        }
+        _ => Err((
+            ServerConfigError::ParseError(
+                "expected a list of servers, or a map with `defaults` and `servers`".into(),
+            ),
+            Vec::new(),
+        )),
+    }
+}
+
+/// Longest first, so that a secret containing another one is hidden as a whole
+fn sort_secrets(secrets: &mut Vec<String>) {
+    secrets.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    secrets.dedup();
+}
+
+/// Deserialize a server, after interpolating its fields and applying the defaults.
+///
+/// The defaults have already been interpolated, and are applied afterwards so that they are not
+/// interpolated twice. Problems are located relative to the server.
+fn parse_server(
+    mut server: Value,
+    defaults: &Mapping,
+    index: usize,
+    dir: &Path,
+    variables: &Variables,
+) -> Result<Server> {
+    let description = describe_server(&server, index);
+    let (check_secrets, mut secrets) =
+        interpolate_server(&mut server, &description, dir, variables)?;
+    secrets.extend(
+        check_secrets
+            .iter()
+            .flat_map(|(_, _, check_secrets)| check_secrets.iter().cloned()),
+    );
+    sort_secrets(&mut secrets);
+    apply_defaults(&mut server, defaults);
+
+    let mut server: Server = serde_path_to_error::deserialize(server).map_err(|e| {
+        let path = diagnostics::serde_path(e.path());
+        // Invalid values are quoted in the message
+        let mut message = e.into_inner().to_string();
+        servers::redact(&mut message, &secrets);
+        (ServerConfigError::ParseError(message), path)
+    })?;
+
+    for (list, index, mut check_secrets) in check_secrets {
+        let checks = match list.as_str() {
+            "check" => &mut server.check,
+            _ => &mut server.down_check,
+        };
+        if let Some(check) = checks.get_mut(index) {
+            sort_secrets(&mut check_secrets);
+            check.secrets = check_secrets;
+        }
+    }
+    server.secrets = secrets;
+
+    Ok(server)
+}
+
+/// Whether a file in an included directory is a config file
+fn is_config_file(path: &Path) -> bool {
+    path.is_file() && Format::from_path(path).is_some()
+}
+
+/// The config files in an included directory, in name order so that the wake order is stable
+fn config_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    files.retain(|path| is_config_file(path));
+    files.sort();
+    Ok(files)
+}
+
+/// The servers read from a config file and the files it includes, with every problem found in
+/// them so far
+#[derive(Debug, Default)]
+pub struct Config {
+    pub servers: Vec<Server>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Names of the servers that could not be read, which other servers can still depend on
+    pub invalid: HashSet<String>,
+    /// Tags of the servers that could not be read
+    pub invalid_tags: HashSet<String>,
+    sources: HashMap<PathBuf, SourceMap>,
+    /// Files already read, a file included from several places is only read once
+    loaded: HashSet<PathBuf>,
+    /// The file being read and the files including it, from the top-level file down, to detect
+    /// include cycles
+    including: Vec<(PathBuf, PathBuf)>,
+    /// Variables the config files are interpolated with
+    variables: Variables,
+}
+
+/// The include entry that named a file, problems reading the file are reported there
+type IncludedBy<'a> = Option<(&'a Path, Vec<Segment>)>;
+
+impl Config {
+    fn position(&self, file: &Path, path: &[Segment]) -> Option<Position> {
+        self.sources.get(file)?.find(path)
+    }
+
+    fn report(
+        &mut self,
+        file: &Path,
+        position: Option<Position>,
+        server: Option<&str>,
+        error: ServerConfigError,
+    ) {
+        let source = self.sources.get(file);
+        let mut diagnostic =
+            Diagnostic::new(error).at(file, source.unwrap_or(&SourceMap::default()), position);
+        if let Some(server) = server {
+            diagnostic = diagnostic.in_server(server);
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn report_file(&mut self, path: &Path, included_by: &IncludedBy, message: String) {
+        let error = ServerConfigError::ParseError(message);
+        match included_by {
+            Some((file, include)) => {
+                let position = self.position(file, include);
+                self.report(file, position, None, error);
+            }
+            None => self.report(path, None, None, error),
+        }
+    }
+
+    /// A problem with a server that was read successfully, at `path` relative to the server
+    pub fn diagnostic(
+        &self,
+        server: &Server,
+        path: &[Segment],
+        error: ServerConfigError,
+    ) -> Diagnostic {
+        let diagnostic = Diagnostic::new(error);
+        let diagnostic = match &server.source {
+            Some(source) => {
+                let path = [source.path.as_slice(), path].concat();
+                let position = self.position(&source.file, &path);
+                let map = self.sources.get(&source.file);
+                diagnostic.at(&source.file, map.unwrap_or(&SourceMap::default()), position)
+            }
+            None => diagnostic,
+        };
+        diagnostic.in_server(&server.name)
+    }
+
+    /// Where a server was defined, to point at the first definition of a duplicate
+    pub fn describe(&self, server: &Server) -> String {
+        let Some(source) = &server.source else {
+            return "the same file".to_string();
+        };
+        match self.position(&source.file, &source.path) {
+            Some(position) => format!(
+                "{}:{}:{}",
+                source.file.display(),
+                position.line,
+                position.column
+            ),
+            None => source.file.display().to_string(),
+        }
+    }
+
+    /// Read the servers from `path`, then from the files it includes.
+    ///
+    /// Included files inherit the defaults of the file including them, and can override them
+    /// with a `defaults` section of their own.
+    fn load_file(
+        &mut self,
+        path: &Path,
+        format: Format,
+        inherited: &Mapping,
+        included_by: IncludedBy,
+    ) {
+        let canonical = match fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(e) => {
+                return self.report_file(path, &included_by, format!("{}: {}", path.display(), e))
+            }
+        };
+        if let Some(start) = self
+            .including
+            .iter()
+            .position(|(file, _)| *file == canonical)
+        {
+            let cycle: Vec<String> = self.including[start..]
+                .iter()
+                .map(|(_, path)| path.display().to_string())
+                .chain([path.display().to_string()])
+                .collect();
+            let message = format!("include cycle: {}", cycle.join(" -> "));
+            return self.report_file(path, &included_by, message);
+        }
+        // The servers of a file included from several places, e.g. a file of common servers,
+        // were already read the first time it was included
+        if !self.loaded.insert(canonical.clone()) {
+            return;
+        }
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                return self.report_file(path, &included_by, format!("{}: {}", path.display(), e))
+            }
+        };
+
+        self.sources
+            .insert(path.to_path_buf(), format.source_map(&content));
+        let document = match format.parse(&content) {
+            Ok(document) => document,
+            Err((message, position)) => {
+                return self.report(path, position, None, ServerConfigError::ParseError(message))
+            }
+        };
+
+        // Included paths and secret files are relative to the file referencing them
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let (document, servers_path) = match parse_document(document, dir, &self.variables) {
+            Ok(document) => document,
+            Err((error, at)) => {
+                let position = self.position(path, &at);
+                return self.report(path, position, None, error);
+            }
+        };
+
+        let mut defaults = inherited.clone();
+        defaults.extend(document.defaults);
+
+        for (index, server) in document.servers.into_iter().enumerate() {
+            let mut server_path = servers_path.clone();
+            server_path.push(Segment::Index(index));
+            let description = describe_server(&server, index);
+            let tags = server_tags(&server);
+
+            match parse_server(server, &defaults, index, dir, &self.variables) {
+                Ok(mut server) => {
+                    server.source = Some(Source {
+                        file: path.to_path_buf(),
+                        path: server_path,
+                    });
+                    self.servers.push(server);
+                }
+                Err((error, at)) => {
+                    let position = self.position(path, &[server_path, at].concat());
+                    self.report(path, position, Some(&description), error);
+                    self.invalid.insert(description);
+                    self.invalid_tags.extend(tags);
+                }
+            }
+        }
+
+        self.including.push((canonical, path.to_path_buf()));
+        for (index, include) in document.include.into_iter().enumerate() {
+            let include = dir.join(include);
+            let entry = vec![Segment::key("include"), Segment::Index(index)];
+            let files = if include.is_dir() {
+                match config_files(&include) {
+                    Ok(files) => files,
+                    Err(e) => {
+                        let message = format!("{}: {}", include.display(), e);
+                        self.report_file(&include, &Some((path, entry)), message);
+                        continue;
+                    }
+                }
+            } else {
+                vec![include]
+            };
+            for file in files {
+                let format = Format::from_path(&file).unwrap_or(Format::Yaml);
+                self.load_file(&file, format, &defaults, Some((path, entry.clone())));
+            }
+        }
+        self.including.pop();
+    }
+}
+
+/// Read the servers from a config file and every file it includes, collecting every problem
+/// instead of stopping at the first one.
+///
+/// `format` overrides the format of the config file itself, otherwise it is picked by the file
+/// extension, and files with an unknown extension are read as YAML. Included files always use
+/// their extension.
+pub fn load_servers(path: &Path, format: Option<Format>) -> Config {
+    let format = format
+        .or_else(|| Format::from_path(path))
+        .unwrap_or(Format::Yaml);
+    let mut config = Config {
+        variables: interpolation::environment(),
+        ..Default::default()
+    };
+    config.load_file(path, format, &Mapping::new(), None);
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the servers of a YAML document with the given variables, stopping at the first problem
+    fn interpolate_servers(
+        content: &str,
+        variables: &[(&str, &str)],
+    ) -> std::result::Result<Vec<Server>, ServerConfigError> {
+        let variables: Variables = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let document = Format::Yaml
+            .parse(content)
+            .map_err(|(message, _)| ServerConfigError::ParseError(message))?;
+        let (document, _) =
+            parse_document(document, Path::new(""), &variables).map_err(|(e, _)| e)?;
+        document
+            .servers
+            .into_iter()
+            .enumerate()
+            .map(|(index, server)| {
+                parse_server(server, &document.defaults, index, Path::new(""), &variables)
+                    .map_err(|(e, _)| e)
+            })
+            .collect()
+    }
+
+    /// Read the servers of a YAML document, stopping at the first problem
+    fn parse_servers(content: &str) -> std::result::Result<Vec<Server>, ServerConfigError> {
+        interpolate_servers(content, &[])
+    }
+
+    #[test]
+    fn test_bare_list() {
+        let servers = parse_servers(
+            r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+        "#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(1, servers.len());
+        assert_eq!(Some("eth0".to_string()), servers[0].interface);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let servers = parse_servers(
+            r#"
+        defaults:
+          interface: "eth1"
+          vlan: 20
+          transport: udp
+          retry: 2s
+          timeout: 1m
+
+        servers:
+          - name: "server1"
+            mac: "00:11:22:33:44:55"
+            check:
+              - type: port
+                ip: "10.0.0.1"
+                port: 22
+            down_check:
+              - type: port
+                ip: "10.0.0.1"
+                port: 22
+                timeout: 10m
+
+          - name: "server2"
+            mac: "66:77:88:99:AA:BB"
+            interface: "eth0"
+            vlan: ~
+            transport: ethernet
+            check:
+              - type: port
+                ip: "10.0.0.2"
+                port: 22
+                retry: 10s
+        "#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(Some("eth1".to_string()), servers[0].interface);
+        assert_eq!(20, servers[0].vlan.unwrap().tag.id);
+        assert_eq!(wol::Transport::Udp, servers[0].transport);
+        assert_eq!(Duration::from_secs(2), servers[0].check[0].retry);
+        assert_eq!(Duration::from_secs(60), servers[0].check[0].timeout);
+        assert_eq!(Duration::from_secs(2), servers[0].down_check[0].retry);
+        assert_eq!(Duration::from_secs(600), servers[0].down_check[0].timeout);
+
+        // Values set on the server win, even when they are the built-in defaults
+        assert_eq!(Some("eth0".to_string()), servers[1].interface);
+        assert_eq!(None, servers[1].vlan);
+        assert_eq!(wol::Transport::Ethernet, servers[1].transport);
+        assert_eq!(Duration::from_secs(10), servers[1].check[0].retry);
+        assert_eq!(Duration::from_secs(60), servers[1].check[0].timeout);
+    }
+
+    #[test]
+    fn test_document_without_defaults() {
+        let servers = parse_servers(
+            r#"
+        servers:
+          - name: "server1"
+            mac: "00:11:22:33:44:55"
+        "#,
+        )
+        .expect("Failed to parse config");
+
+        assert_eq!(1, servers.len());
+        assert_eq!(None, servers[0].interface);
+    }
+
+    #[test]
+    fn test_invalid_documents() {
+        let errors = [
+            // Not a default that can be set
+            r#"
+        defaults:
+          mac: "00:11:22:33:44:55"
+        servers: []
+        "#,
+            // Invalid default
+            r#"
+        defaults:
+          vlan: 5000
+        servers: []
+        "#,
+            // Unknown section
+            r#"
+        server:
+          - name: "server1"
+        "#,
+            "just a string",
+        ];
+        for yaml_data in errors {
+            assert!(
+                matches!(
+                    parse_servers(yaml_data),
+                    Err(ServerConfigError::ParseError(_))
+                ),
+                "Expected {} to be rejected",
+                yaml_data
+            );
+        }
+
+        // Problems are located relative to the server
+        let server = serde_yaml_ng::from_str(
+            r#"
+        name: "storage"
+        mac: "ff:ff:ff:ff:ff:ff"
+        "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            parse_server(server, &Mapping::new(), 0, Path::new(""), &Variables::new()),
+            Err((ServerConfigError::ParseError(_), path)) if path == vec![Segment::key("mac")]
+        ));
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let servers = interpolate_servers(
+            r#"
+        defaults:
+          interface: "${RALLYUP_TEST_INTERFACE}"
+
+        servers:
+          - name: "storage"
+            mac: "00:11:22:33:44:55"
+            check:
+              - type: http
+                url: "http://10.0.0.2/health?token=${secret:RALLYUP_TEST_HEALTH_TOKEN}"
+                status: 200
+              - type: ipmi
+                host: "10.0.1.2"
+                username: "${RALLYUP_TEST_BMC_USER:-admin}"
+                password: "${secret:RALLYUP_TEST_BMC_PASSWORD}"
+              - type: shell
+                command: "test -n \"$${HOME}\""
+                status: 0
+        "#,
+            &[
+                ("RALLYUP_TEST_HEALTH_TOKEN", "t0k3n"),
+                ("RALLYUP_TEST_BMC_PASSWORD", "calvin"),
+                ("RALLYUP_TEST_INTERFACE", "eth3"),
+            ],
+        )
+        .expect("Failed to parse config");
+
+        let server = &servers[0];
+        assert_eq!(Some("eth3".to_string()), server.interface);
+
+        let crate::servers::HealthCheckMethod::Http { url, .. } = &server.check[0].method else {
+            panic!("Expected an HTTP check");
+        };
+        assert_eq!("http://10.0.0.2/health?token=t0k3n", url);
+        assert_eq!(vec!["t0k3n"], server.check[0].secrets);
+        assert!(server.check[0]
+            .to_string()
+            .ends_with("[http://10.0.0.2/health?token=***]"));
+
+        let crate::servers::HealthCheckMethod::Ipmi(ipmi) = &server.check[1].method else {
+            panic!("Expected an IPMI check");
+        };
+        assert_eq!("admin", ipmi.username);
+        assert_eq!("calvin", ipmi.password);
+        assert_eq!(vec!["calvin"], server.check[1].secrets);
+
+        let crate::servers::HealthCheckMethod::Shell { command, .. } = &server.check[2].method
+        else {
+            panic!("Expected a shell check");
+        };
+        assert_eq!(r#"test -n "${HOME}""#, command);
+        assert!(server.check[2].secrets.is_empty());
+    }
+
+    #[test]
+    fn test_interpolated_secrets() {
+        let variables = [
+            ("RALLYUP_TEST_BMC_PASSWORD", "calvin"),
+            ("RALLYUP_TEST_HEALTH_TOKEN", "t0k3n"),
+            ("RALLYUP_TEST_BMC_USER", "root"),
+            ("RALLYUP_TEST_MAC", "not-a-mac"),
+        ];
+        let servers = interpolate_servers(
+            r#"
+        - name: "storage"
+          wake:
+            type: ipmi
+            host: 10.0.1.2
+            username: "${RALLYUP_TEST_BMC_USER}"
+            password: "${secret:RALLYUP_TEST_BMC_PASSWORD}"
+          check:
+            - type: http
+              url: "http://10.0.0.2/health?token=${secret:RALLYUP_TEST_HEALTH_TOKEN}"
+              status: 200
+        "#,
+            &variables,
+        )
+        .expect("Failed to parse config");
+
+        // Secrets outside of the health checks are kept with the server
+        let server = &servers[0];
+        assert_eq!(vec!["calvin", "t0k3n"], server.secrets);
+        assert_eq!(
+            "failed to log in as root with *** on 10.0.1.2",
+            server.redact("failed to log in as root with calvin on 10.0.1.2")
+        );
+
+        // Invalid values are hidden in parse errors as well
+        let result = interpolate_servers(
+            r#"
+        - name: "storage"
+          mac: "${secret:RALLYUP_TEST_MAC}"
+        "#,
+            &variables,
+        );
+        let Err(ServerConfigError::ParseError(message)) = result else {
+            panic!("Expected a parse error");
+        };
+        assert!(message.contains("***") && !message.contains("not-a-mac"));
+    }
+
+    #[test]
+    fn test_interpolation_errors() {
+        let result = parse_servers(
+            r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          check:
+            - type: port
+              ip: "10.0.0.2"
+              port: 22
+            - type: http
+              url: "http://10.0.0.2/health?token=${RALLYUP_TEST_UNSET_TOKEN}"
+              status: 200
+        "#,
+        );
+        assert!(matches!(
+            result,
+            Err(ServerConfigError::BadInterpolation(location, InterpolationError::MissingVariable(name)))
+                if location == "server storage, check #2" && name == "RALLYUP_TEST_UNSET_TOKEN"
+        ));
+
+        let result = parse_servers(
+            r#"
+        defaults:
+          interface: "${RALLYUP_TEST_UNSET_TOKEN}"
+        servers: []
+        "#,
+        );
+        assert!(matches!(
+            result,
+            Err(ServerConfigError::BadInterpolation(location, _)) if location == "defaults, interface"
+        ));
+    }
+
+    #[test]
+    fn test_syntax_error_positions() {
+        let cases = [
+            (Format::Yaml, "- name: web\n  mac: 00: 11\n", (2, 10)),
+            (
+                Format::Json,
+                "{\n  \"servers\": [\n    { \"name\" \"web\" }\n  ]\n}\n",
+                (3, 14),
+            ),
+            (
+                Format::Toml,
+                "[[servers]]\nname = \"web\"\nmac = 00:11\n",
+                (3, 12),
+            ),
+        ];
+        for (format, content, (line, column)) in cases {
+            let (message, position) = format.parse(content).unwrap_err();
+            assert_eq!(
+                Some((line, column)),
+                position.map(|p| (p.line, p.column)),
+                "{:?}",
+                format
+            );
+            assert!(!message.contains("at line"), "{}", message);
+        }
+    }
+}
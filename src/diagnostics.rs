@@ -0,0 +1,415 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use yaml_rust2::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+use crate::servers::ServerConfigError;
+
+/// A step on the way from the top of a config file to one of its values
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Segment {
+    pub fn key(key: &str) -> Segment {
+        Segment::Key(key.to_string())
+    }
+}
+
+/// Convert the path serde gives for a deserialization error, up to the first step it could not
+/// follow, e.g. into a flattened field
+pub fn serde_path(path: &serde_path_to_error::Path) -> Vec<Segment> {
+    path.iter()
+        .map_while(|segment| match segment {
+            serde_path_to_error::Segment::Seq { index } => Some(Segment::Index(*index)),
+            serde_path_to_error::Segment::Map { key } => Some(Segment::Key(key.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Where a problem is in a config file, with the line and column starting at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    /// How many characters to underline
+    pub length: usize,
+}
+
+impl Position {
+    /// Position of the byte `offset` into `text`
+    pub fn from_offset(text: &str, offset: usize, length: usize) -> Position {
+        let before = &text[..offset.min(text.len())];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        Position {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            length,
+        }
+    }
+}
+
+/// Frames of the YAML document that is being walked through
+enum Frame {
+    /// The key of the value that is being read, the first key also locates the mapping itself
+    Mapping {
+        key: Option<String>,
+        located: bool,
+    },
+    Sequence(usize),
+}
+
+/// Records the position of every value, and every key, of a YAML document
+#[derive(Default)]
+struct Locator<'a> {
+    lines: Vec<&'a str>,
+    frames: Vec<Frame>,
+    path: Vec<Segment>,
+    values: HashMap<Vec<Segment>, Position>,
+    keys: HashMap<Vec<Segment>, Position>,
+}
+
+impl Locator<'_> {
+    fn position(&self, mark: Marker, scalar: Option<&str>) -> Position {
+        let line = self.lines.get(mark.line() - 1).copied().unwrap_or_default();
+        let rest: Vec<char> = line.chars().skip(mark.col()).collect();
+        let length = match (scalar, rest.first()) {
+            // Quoted scalars are underlined with their quotes
+            (Some(_), Some(quote @ ('"' | '\''))) => rest[1..]
+                .iter()
+                .position(|c| c == quote)
+                .map_or(rest.len(), |end| end + 2),
+            (Some(value), _) => value.chars().count().min(rest.len()),
+            // Collections are underlined to the end of the line they start on
+            (None, _) => rest.iter().collect::<String>().trim_end().chars().count(),
+        };
+        Position {
+            line: mark.line(),
+            column: mark.col() + 1,
+            length: length.max(1),
+        }
+    }
+
+    fn child_path(&self) -> Vec<Segment> {
+        let mut path = self.path.clone();
+        match self.frames.last() {
+            Some(Frame::Mapping { key: Some(key), .. }) => path.push(Segment::Key(key.clone())),
+            Some(Frame::Sequence(index)) => path.push(Segment::Index(*index)),
+            _ => {}
+        }
+        path
+    }
+
+    /// Move on to the next key, or item, of the enclosing collection
+    fn value_done(&mut self) {
+        match self.frames.last_mut() {
+            Some(Frame::Mapping { key, .. }) => *key = None,
+            Some(Frame::Sequence(index)) => *index += 1,
+            None => {}
+        }
+    }
+
+    fn on_scalar(&mut self, value: &str, mark: Marker) {
+        let position = self.position(mark, Some(value));
+        let line = self.position(mark, None);
+        if let Some(Frame::Mapping { key, located }) = self.frames.last_mut() {
+            if key.is_none() {
+                *key = Some(value.to_string());
+                if !*located {
+                    *located = true;
+                    self.values.insert(self.path.clone(), line);
+                }
+                let mut path = self.path.clone();
+                path.push(Segment::key(value));
+                self.keys.insert(path, position);
+                return;
+            }
+        }
+        self.values.insert(self.child_path(), position);
+        self.value_done();
+    }
+
+    fn on_collection_start(&mut self, frame: Frame, mark: Marker) {
+        let path = self.child_path();
+        // Block mappings are located by their first key instead
+        if matches!(frame, Frame::Sequence(_)) {
+            let position = self.position(mark, None);
+            self.values.insert(path.clone(), position);
+        }
+        if !self.frames.is_empty() {
+            self.path = path;
+        }
+        self.frames.push(frame);
+    }
+
+    fn on_collection_end(&mut self) {
+        self.frames.pop();
+        if !self.frames.is_empty() {
+            self.path.pop();
+        }
+        self.value_done();
+    }
+}
+
+impl MarkedEventReceiver for Locator<'_> {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => self.on_scalar(&value, mark),
+            Event::Alias(_) => self.on_scalar("*", mark),
+            Event::SequenceStart(..) => self.on_collection_start(Frame::Sequence(0), mark),
+            Event::MappingStart(..) => self.on_collection_start(
+                Frame::Mapping {
+                    key: None,
+                    located: false,
+                },
+                mark,
+            ),
+            Event::SequenceEnd | Event::MappingEnd => self.on_collection_end(),
+            _ => {}
+        }
+    }
+}
+
+/// The text of a config file, with the positions of its values when it is YAML or JSON
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    lines: Vec<String>,
+    values: HashMap<Vec<Segment>, Position>,
+    keys: HashMap<Vec<Segment>, Position>,
+}
+
+impl SourceMap {
+    /// Only the text, e.g. for TOML files
+    pub fn new(text: &str) -> SourceMap {
+        SourceMap {
+            lines: text.lines().map(str::to_string).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The text and the positions of a YAML document, JSON documents are YAML as well
+    pub fn yaml(text: &str) -> SourceMap {
+        let mut locator = Locator {
+            lines: text.lines().collect(),
+            ..Default::default()
+        };
+        // Documents that fail to parse are reported by the deserializer instead
+        if Parser::new_from_str(text)
+            .load(&mut locator, false)
+            .is_err()
+        {
+            return SourceMap::new(text);
+        }
+        SourceMap {
+            lines: text.lines().map(str::to_string).collect(),
+            values: locator.values,
+            keys: locator.keys,
+        }
+    }
+
+    /// Position of the value at `path`, or of the closest value containing it.
+    ///
+    /// Values in a mapping are located by their key, so that the name of the field is underlined.
+    pub fn find(&self, path: &[Segment]) -> Option<Position> {
+        (0..=path.len()).rev().find_map(|end| {
+            let path = &path[..end];
+            match path.last() {
+                Some(Segment::Key(_)) => self.keys.get(path).or_else(|| self.values.get(path)),
+                _ => self.values.get(path),
+            }
+            .copied()
+        })
+    }
+
+    fn line(&self, line: usize) -> Option<&str> {
+        self.lines.get(line.checked_sub(1)?).map(String::as_str)
+    }
+}
+
+/// A problem with the config, with where it was found
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub error: ServerConfigError,
+    pub file: Option<String>,
+    pub server: Option<String>,
+    pub position: Option<Position>,
+    /// The line of the config file the problem is on
+    snippet: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(error: ServerConfigError) -> Diagnostic {
+        Diagnostic {
+            error,
+            file: None,
+            server: None,
+            position: None,
+            snippet: None,
+        }
+    }
+
+    /// Point the problem at `position` in `file`, when it is known
+    pub fn at(mut self, file: &Path, source: &SourceMap, position: Option<Position>) -> Self {
+        self.file = Some(file.display().to_string());
+        self.snippet = position.and_then(|p| source.line(p.line).map(str::to_string));
+        self.position = position;
+        self
+    }
+
+    pub fn in_server(mut self, name: &str) -> Self {
+        self.server = Some(name.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "error: {}", self.error)?;
+
+        let server = match &self.server {
+            Some(name) => format!(" (server {})", name),
+            None => String::new(),
+        };
+        let Some(file) = &self.file else {
+            return write!(f, "  in the config{}", server);
+        };
+        let (Some(position), Some(snippet)) = (self.position, &self.snippet) else {
+            return write!(f, "  --> {}{}", file, server);
+        };
+
+        // Annotated line of the config, like the compiler does
+        let gutter = " ".repeat(position.line.to_string().len());
+        writeln!(
+            f,
+            "{} --> {}:{}:{}{}",
+            gutter, file, position.line, position.column, server
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", position.line, snippet)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(position.column - 1),
+            "^".repeat(position.length)
+        )
+    }
+}
+
+/// All the problems with the config, one after the other
+pub fn render(diagnostics: &[Diagnostic]) -> String {
+    let count = match diagnostics.len() {
+        1 => "1 problem".to_string(),
+        count => format!("{} problems", count),
+    };
+    let rendered: Vec<_> = diagnostics.iter().map(Diagnostic::to_string).collect();
+    format!(
+        "Found {} in the config:\n\n{}",
+        count,
+        rendered.join("\n\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"defaults:
+  vlan: 20
+servers:
+  - name: "storage"
+    mac: "00:11:22:33:44:55"
+    depends: [firewall, "router"]
+    check:
+      - type: port
+        port: 22
+"#;
+
+    fn path(segments: &[&str]) -> Vec<Segment> {
+        segments
+            .iter()
+            .map(|segment| match segment.parse() {
+                Ok(index) => Segment::Index(index),
+                Err(_) => Segment::key(segment),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_source_map() {
+        let source = SourceMap::yaml(CONFIG);
+        let find = |segments: &[&str]| source.find(&path(segments)).unwrap();
+
+        let at = |line, column, length| Position {
+            line,
+            column,
+            length,
+        };
+        assert_eq!(at(2, 3, 4), find(&["defaults", "vlan"]));
+        assert_eq!(at(4, 5, 15), find(&["servers", "0"]));
+        assert_eq!(at(5, 5, 3), find(&["servers", "0", "mac"]));
+        assert_eq!(at(6, 15, 8), find(&["servers", "0", "depends", "0"]));
+        assert_eq!(at(6, 25, 8), find(&["servers", "0", "depends", "1"]));
+        assert_eq!(at(8, 9, 10), find(&["servers", "0", "check", "0"]));
+        assert_eq!(at(9, 9, 4), find(&["servers", "0", "check", "0", "port"]));
+
+        // Paths that do not exist are located by the closest value containing them
+        assert_eq!(at(5, 5, 3), find(&["servers", "0", "mac", "oui"]));
+        assert_eq!(at(4, 5, 15), find(&["servers", "0", "wake"]));
+
+        // JSON is located as well
+        let source = SourceMap::yaml(r#"[{ "name": "web", "mac": "00" }]"#);
+        assert_eq!(Some(at(1, 19, 5)), source.find(&path(&["0", "mac"])));
+
+        assert_eq!(None, SourceMap::new(CONFIG).find(&path(&["servers"])));
+    }
+
+    #[test]
+    fn test_position_from_offset() {
+        let offset = CONFIG.find("mac").unwrap();
+        assert_eq!(
+            Position {
+                line: 5,
+                column: 5,
+                length: 3
+            },
+            Position::from_offset(CONFIG, offset, 3)
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let source = SourceMap::yaml(CONFIG);
+        let diagnostic = Diagnostic::new(ServerConfigError::UndefinedDependency("router".into()))
+            .at(
+                Path::new("rallyup.yaml"),
+                &source,
+                source.find(&path(&["servers", "0", "depends", "1"])),
+            )
+            .in_server("storage");
+
+        assert_eq!(
+            r#"error: Found undefined dependency: router
+  --> rallyup.yaml:6:25 (server storage)
+  |
+6 |     depends: [firewall, "router"]
+  |                         ^^^^^^^^"#,
+            diagnostic.to_string()
+        );
+
+        let diagnostic = Diagnostic::new(ServerConfigError::ParseError("missing".into())).at(
+            Path::new("missing.yaml"),
+            &SourceMap::default(),
+            None,
+        );
+        assert_eq!(
+            "error: Failed to parse config file: missing\n  --> missing.yaml",
+            diagnostic.to_string()
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use reqwest::{Client, Method, Url};
+use serde::Deserialize;
+use std::{collections::BTreeMap, time::Duration};
+
+use thiserror::Error;
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_request_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Debug, Error)]
+pub enum HttpPowerError {
+    #[error("Invalid HTTP power configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("HTTP power request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("HTTP power request failed with status {0}: {1}")]
+    RequestFailed(u16, String),
+}
+
+type Result<T> = std::result::Result<T, HttpPowerError>;
+
+/// Switch on an outlet of a smart plug or PDU with a single HTTP request, e.g.
+/// `http://plug/cm?cmnd=Power%20On` for Tasmota or `http://plug/relay/0?turn=on` for Shelly
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpPowerConfig {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// Basic authentication, which most PDU web interfaces use
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Accept self-signed and otherwise invalid certificates
+    #[serde(default)]
+    pub insecure: bool,
+    /// Status code the device answers with once the outlet is switched on.
+    /// Any 2xx status is accepted when omitted.
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default = "default_request_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl HttpPowerConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.url()?;
+        self.method()?;
+        if self.password.is_some() && self.username.is_none() {
+            return Err(HttpPowerError::InvalidConfig(
+                "`password` requires a `username`".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn url(&self) -> Result<Url> {
+        let url =
+            Url::parse(&self.url).map_err(|e| HttpPowerError::InvalidConfig(e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(HttpPowerError::InvalidConfig(format!(
+                "{} is not an http(s) URL",
+                self.url
+            )));
+        }
+        Ok(url)
+    }
+
+    fn method(&self) -> Result<Method> {
+        Method::from_bytes(self.method.to_uppercase().as_bytes()).map_err(|_| {
+            HttpPowerError::InvalidConfig(format!("{} is not an HTTP method", self.method))
+        })
+    }
+}
+
+/// Send the configured request and check that the device accepted it
+pub async fn power_on(config: &HttpPowerConfig) -> Result<()> {
+    let client = Client::builder()
+        .timeout(config.timeout)
+        .danger_accept_invalid_certs(config.insecure)
+        .build()?;
+
+    let mut request = client.request(config.method()?, config.url()?);
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+    if let Some(username) = &config.username {
+        request = request.basic_auth(username, config.password.as_ref());
+    }
+    if let Some(body) = &config.body {
+        request = request.body(body.clone());
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    let accepted = match config.status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    };
+    if !accepted {
+        let body = response.text().await.unwrap_or_default();
+        return Err(HttpPowerError::RequestFailed(status.as_u16(), body));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(url: &str) -> HttpPowerConfig {
+        HttpPowerConfig {
+            url: url.to_string(),
+            method: default_method(),
+            headers: BTreeMap::new(),
+            body: None,
+            username: None,
+            password: None,
+            insecure: false,
+            status: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_power_on() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/cm?cmnd=Power%20On")
+            .with_status(200)
+            .with_body(r#"{"POWER":"ON"}"#)
+            .create_async()
+            .await;
+
+        let result = power_on(&config(&format!("{}/cm?cmnd=Power%20On", server.url()))).await;
+        assert!(result.is_ok(), "{:?}", result);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_with_body_and_headers() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/rpc/Switch.Set")
+            .match_header("content-type", "application/json")
+            .match_header("x-api-key", "token")
+            // admin:secret
+            .match_header("authorization", "Basic YWRtaW46c2VjcmV0")
+            .match_body(r#"{"id":0,"on":true}"#)
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let mut config = config(&format!("{}/rpc/Switch.Set", server.url()));
+        config.method = "post".into();
+        config.headers = BTreeMap::from([
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("X-Api-Key".to_string(), "token".to_string()),
+        ]);
+        config.body = Some(r#"{"id":0,"on":true}"#.into());
+        config.username = Some("admin".into());
+        config.password = Some("secret".into());
+        config.status = Some(201);
+
+        let result = power_on(&config).await;
+        assert!(result.is_ok(), "{:?}", result);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_failure() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/relay/0?turn=on")
+            .with_status(401)
+            .with_body("Unauthorized")
+            .create_async()
+            .await;
+
+        let result = power_on(&config(&format!("{}/relay/0?turn=on", server.url()))).await;
+        assert!(matches!(
+            result,
+            Err(HttpPowerError::RequestFailed(401, body)) if body == "Unauthorized"
+        ));
+
+        // Success status, but not the one the device is configured to answer with
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/relay/0?turn=on")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let mut config = config(&format!("{}/relay/0?turn=on", server.url()));
+        config.status = Some(204);
+        let result = power_on(&config).await;
+        assert!(matches!(result, Err(HttpPowerError::RequestFailed(200, _))));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(config("http://10.0.0.20/cm?cmnd=Power%20On")
+            .validate()
+            .is_ok());
+        assert!(matches!(
+            config("10.0.0.20").validate(),
+            Err(HttpPowerError::InvalidConfig(_))
+        ));
+
+        let mut bad_method = config("http://10.0.0.20");
+        bad_method.method = "TURN ON".into();
+        assert!(matches!(
+            bad_method.validate(),
+            Err(HttpPowerError::InvalidConfig(_))
+        ));
+
+        let mut password_only = config("http://10.0.0.20");
+        password_only.password = Some("secret".into());
+        assert!(matches!(
+            password_only.validate(),
+            Err(HttpPowerError::InvalidConfig(_))
+        ));
+    }
+}
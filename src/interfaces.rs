@@ -0,0 +1,359 @@
+use pnet::datalink::{self, NetworkInterface};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+
+use thiserror::Error;
+
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+
+#[derive(Debug, Error)]
+pub enum InterfaceSelectionError {
+    #[error("Could not select an interface for {server}: {reasons}")]
+    Undecided { server: String, reasons: String },
+}
+
+type Result<T> = std::result::Result<T, InterfaceSelectionError>;
+
+/// The interface picked for a server, and why it was picked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub interface: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.interface, self.reason)
+    }
+}
+
+/// An IPv4 entry of the kernel routing table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub interface: String,
+    pub destination: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Route {
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.destination) & mask
+    }
+}
+
+// /proc/net/route stores addresses as hex in host byte order, e.g. 0100A8C0 for 192.168.0.1
+fn parse_route_address(hex: &str) -> Option<Ipv4Addr> {
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Ipv4Addr::from(value.to_ne_bytes()))
+}
+
+fn parse_routes(content: &str) -> Vec<Route> {
+    content
+        .lines()
+        // Skip the header
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let mask = parse_route_address(fields[7])?;
+            Some(Route {
+                interface: fields[0].to_string(),
+                destination: parse_route_address(fields[1])?,
+                gateway: parse_route_address(fields[2])?,
+                prefix: u32::from(mask).count_ones() as u8,
+            })
+        })
+        .collect()
+}
+
+/// Read the IPv4 routing table, empty on systems without `/proc/net/route`
+pub fn system_routes() -> Vec<Route> {
+    fs::read_to_string(PROC_NET_ROUTE)
+        .map(|content| parse_routes(&content))
+        .unwrap_or_default()
+}
+
+/// Pick the interface to send a raw WOL frame from for a server without an explicit `interface`.
+///
+/// In order of preference:
+/// 1. the interface with a subnet containing the server's `ip`
+/// 2. the most specific non-default route to the server's `ip` without a gateway
+/// 3. the `default` interface given on the command line
+///
+/// Routes through a gateway, including the default route, are deliberately ignored: a raw
+/// Ethernet frame sent towards a gateway will not reach a machine on another subnet.
+pub fn select_interface(
+    server: &str,
+    ip: Option<IpAddr>,
+    default: Option<&str>,
+    interfaces: &[NetworkInterface],
+    routes: &[Route],
+) -> Result<Selection> {
+    let mut reasons = Vec::new();
+
+    match ip {
+        Some(ip) => {
+            let subnet_match = interfaces.iter().find_map(|iface| {
+                iface
+                    .ips
+                    .iter()
+                    .find(|network| network.contains(ip))
+                    .map(|network| (iface, network))
+            });
+            if let Some((iface, network)) = subnet_match {
+                return Ok(Selection {
+                    interface: iface.name.clone(),
+                    reason: format!("{} is in {}/{}", ip, network.network(), network.prefix()),
+                });
+            }
+            reasons.push(format!("no interface has a subnet containing {}", ip));
+
+            if let IpAddr::V4(ipv4) = ip {
+                let (direct, gatewayed): (Vec<&Route>, Vec<&Route>) = routes
+                    .iter()
+                    .filter(|route| route.prefix > 0 && route.contains(ipv4))
+                    .partition(|route| route.gateway == Ipv4Addr::UNSPECIFIED);
+                let route = direct.into_iter().max_by_key(|route| route.prefix);
+                if let Some(route) = route {
+                    return Ok(Selection {
+                        interface: route.interface.clone(),
+                        reason: format!(
+                            "route to {}/{} for {}",
+                            route.destination, route.prefix, ip
+                        ),
+                    });
+                }
+                match gatewayed.into_iter().max_by_key(|route| route.prefix) {
+                    Some(route) => reasons.push(format!(
+                        "{} is only reachable through gateway {}",
+                        ip, route.gateway
+                    )),
+                    None => reasons.push(format!("only the default route leads to {}", ip)),
+                }
+            }
+        }
+        None => reasons.push("no `ip` configured".to_string()),
+    }
+
+    if let Some(default) = default {
+        return Ok(Selection {
+            interface: default.to_string(),
+            reason: "default interface".to_string(),
+        });
+    }
+    reasons.push("no default interface given with --interface".to_string());
+
+    Err(InterfaceSelectionError::Undecided {
+        server: server.to_string(),
+        reasons: reasons.join(", "),
+    })
+}
+
+/// Look up a network interface of this machine by name
+pub fn find_interface(name: &str) -> Option<NetworkInterface> {
+    datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == name)
+}
+
+/// An interface that does not need to exist on the machine running the tests
+#[cfg(test)]
+pub fn test_interface() -> NetworkInterface {
+    NetworkInterface {
+        name: "test0".into(),
+        description: String::new(),
+        index: 0,
+        mac: Some(pnet::util::MacAddr::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x01)),
+        ips: vec![],
+        flags: 0,
+    }
+}
+
+/// Same as [`select_interface`] using the interfaces and routing table of this machine
+pub fn select_system_interface(
+    server: &str,
+    ip: Option<IpAddr>,
+    default: Option<&str>,
+) -> Result<Selection> {
+    select_interface(
+        server,
+        ip,
+        default,
+        &datalink::interfaces(),
+        &system_routes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index: 0,
+            mac: None,
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            flags: 0,
+        }
+    }
+
+    const ROUTES: &str =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth1\t0000100A\t0100100A\t0003\t0\t0\t0\t0000FFFF\t0\t0\t0
+";
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = parse_routes(ROUTES);
+        assert_eq!(
+            routes,
+            vec![
+                Route {
+                    interface: "eth0".into(),
+                    destination: Ipv4Addr::new(0, 0, 0, 0),
+                    gateway: Ipv4Addr::new(192, 168, 1, 1),
+                    prefix: 0,
+                },
+                Route {
+                    interface: "eth0".into(),
+                    destination: Ipv4Addr::new(192, 168, 1, 0),
+                    gateway: Ipv4Addr::new(0, 0, 0, 0),
+                    prefix: 24,
+                },
+                Route {
+                    interface: "eth1".into(),
+                    destination: Ipv4Addr::new(10, 16, 0, 0),
+                    gateway: Ipv4Addr::new(10, 16, 0, 1),
+                    prefix: 16,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_interface() {
+        let interfaces = vec![
+            interface("eth0", &["192.168.1.10/24"]),
+            interface("eth1", &["10.0.0.2/24", "fd00::2/64"]),
+        ];
+        let routes = parse_routes(ROUTES);
+
+        // Directly attached subnet
+        let selection = select_interface(
+            "server",
+            Some("10.0.0.20".parse().unwrap()),
+            None,
+            &interfaces,
+            &routes,
+        )
+        .unwrap();
+        assert_eq!(selection.interface, "eth1");
+
+        let selection = select_interface(
+            "server",
+            Some("fd00::20".parse().unwrap()),
+            Some("eth0"),
+            &interfaces,
+            &routes,
+        )
+        .unwrap();
+        assert_eq!(selection.interface, "eth1");
+
+        // Through the routing table, only for routes without a gateway
+        let mut on_link = routes.clone();
+        on_link.push(Route {
+            interface: "eth1".into(),
+            destination: Ipv4Addr::new(172, 16, 0, 0),
+            gateway: Ipv4Addr::UNSPECIFIED,
+            prefix: 12,
+        });
+        let selection = select_interface(
+            "server",
+            Some("172.20.5.5".parse().unwrap()),
+            None,
+            &interfaces,
+            &on_link,
+        )
+        .unwrap();
+        assert_eq!(selection.interface, "eth1");
+
+        // Behind a router, fall back to the default interface
+        let selection = select_interface(
+            "server",
+            Some("10.16.5.5".parse().unwrap()),
+            Some("eth0"),
+            &interfaces,
+            &routes,
+        )
+        .unwrap();
+        assert_eq!(selection.interface, "eth0");
+
+        let result = select_interface(
+            "server",
+            Some("10.16.5.5".parse().unwrap()),
+            None,
+            &interfaces,
+            &routes,
+        );
+        match result {
+            Err(InterfaceSelectionError::Undecided { reasons, .. }) => {
+                assert!(reasons.contains("10.16.5.5 is only reachable through gateway 10.16.0.1"));
+            }
+            _ => panic!("Expected the interface selection to be undecided"),
+        }
+
+        // Only the default route matches, fall back to the default interface
+        let selection = select_interface(
+            "server",
+            Some("8.8.8.8".parse().unwrap()),
+            Some("eth0"),
+            &interfaces,
+            &routes,
+        )
+        .unwrap();
+        assert_eq!(
+            selection,
+            Selection {
+                interface: "eth0".into(),
+                reason: "default interface".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_select_interface_undecided() {
+        let interfaces = vec![interface("eth0", &["192.168.1.10/24"])];
+        let routes = parse_routes(ROUTES);
+
+        let result = select_interface(
+            "server",
+            Some("8.8.8.8".parse().unwrap()),
+            None,
+            &interfaces,
+            &routes,
+        );
+        match result {
+            Err(InterfaceSelectionError::Undecided { server, reasons }) => {
+                assert_eq!(server, "server");
+                assert!(reasons.contains("no interface has a subnet containing 8.8.8.8"));
+                assert!(reasons.contains("only the default route leads to 8.8.8.8"));
+            }
+            _ => panic!("Expected the interface selection to be undecided"),
+        }
+
+        let result = select_interface("server", None, None, &interfaces, &routes);
+        assert!(matches!(
+            result,
+            Err(InterfaceSelectionError::Undecided { .. })
+        ));
+    }
+}
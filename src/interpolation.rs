@@ -0,0 +1,207 @@
+use std::{collections::HashMap, env, fs, io, path::Path};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InterpolationError {
+    #[error("environment variable {0} is not set")]
+    MissingVariable(String),
+
+    #[error("failed to read {0}: {1}")]
+    FileError(String, io::Error),
+
+    #[error("`${{{0}}}` is not a variable or file reference")]
+    InvalidReference(String),
+
+    #[error("`${{` is not closed with `}}` in {0:?}")]
+    Unterminated(String),
+}
+
+type Result<T> = std::result::Result<T, InterpolationError>;
+
+/// The variables `${VAR}` references are looked up in, by name
+pub type Variables = HashMap<String, String>;
+
+/// The variables of the process environment, leaving out the ones that are not valid unicode
+pub fn environment() -> Variables {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+/// Replace the `${VAR}`, `${VAR:-default}`, `${secret:VAR}` and `${file:/path}` references in
+/// `text`.
+///
+/// `$${` is written as a literal `${`. Variables are looked up in `variables`, and relative file
+/// paths are resolved against `dir`. Values read from files, or from variables marked with
+/// `secret:`, are added to `secrets` so that they can be hidden when the config is shown; other
+/// variables and defaults written in the config are not secret.
+pub fn interpolate(
+    text: &str,
+    dir: &Path,
+    variables: &Variables,
+    secrets: &mut Vec<String>,
+) -> Result<String> {
+    let mut interpolated = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        interpolated.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            interpolated.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| InterpolationError::Unterminated(text.to_string()))?;
+            interpolated.push_str(&resolve(&after[..end], dir, variables, secrets)?);
+            rest = &after[end + 1..];
+        } else {
+            interpolated.push('$');
+            rest = &rest[1..];
+        }
+    }
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
+/// Empty values are never secret, hiding them would hide everything around them instead
+fn add_secret(secrets: &mut Vec<String>, value: &str) {
+    if !value.is_empty() {
+        secrets.push(value.to_string());
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn resolve(
+    reference: &str,
+    dir: &Path,
+    variables: &Variables,
+    secrets: &mut Vec<String>,
+) -> Result<String> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        let path = dir.join(path);
+        let content = fs::read_to_string(&path)
+            .map_err(|e| InterpolationError::FileError(path.display().to_string(), e))?;
+        // Secret files usually end with a newline that is not part of the secret
+        let value = content.trim_end_matches(['\n', '\r']).to_string();
+        add_secret(secrets, &value);
+        return Ok(value);
+    }
+
+    let (reference, secret) = match reference.strip_prefix("secret:") {
+        Some(reference) => (reference, true),
+        None => (reference, false),
+    };
+    let (name, default) = match reference.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (reference, None),
+    };
+    if !is_variable_name(name) {
+        return Err(InterpolationError::InvalidReference(reference.to_string()));
+    }
+
+    match (variables.get(name), default) {
+        // Like the shell, the default is also used for variables that are set but empty
+        (Some(value), Some(default)) if value.is_empty() => Ok(default.to_string()),
+        (Some(value), _) => {
+            if secret {
+                add_secret(secrets, value);
+            }
+            Ok(value.clone())
+        }
+        (None, Some(default)) => Ok(default.to_string()),
+        (None, None) => Err(InterpolationError::MissingVariable(name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Variables {
+        [("TOKEN", "s3cret"), ("PORT", "80"), ("EMPTY", "")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let dir = env::temp_dir();
+        let file = format!("rallyup-test-{}.secret", std::process::id());
+        fs::write(dir.join(&file), "hunter2\n").unwrap();
+
+        let variables = variables();
+        let mut secrets = Vec::new();
+        let cases = [
+            ("http://10.0.0.1/health", "http://10.0.0.1/health"),
+            (
+                "http://10.0.0.1/health?token=${TOKEN}",
+                "http://10.0.0.1/health?token=s3cret",
+            ),
+            ("${UNSET:-admin}", "admin"),
+            ("${EMPTY:-admin}", "admin"),
+            ("${TOKEN:-admin}", "s3cret"),
+            ("${UNSET:-}", ""),
+            ("${secret:TOKEN}", "s3cret"),
+            ("${secret:UNSET:-admin}", "admin"),
+            ("${secret:EMPTY}", ""),
+            ("10.0.0.1:${PORT}", "10.0.0.1:80"),
+            (&format!("${{file:{}}}", file), "hunter2"),
+            ("echo $HOME $${HOME} $", "echo $HOME ${HOME} $"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(
+                expected,
+                interpolate(text, &dir, &variables, &mut secrets).unwrap(),
+                "{}",
+                text
+            );
+        }
+        // Only the marked variables and the files are secret
+        assert_eq!(vec!["s3cret", "hunter2"], secrets);
+
+        fs::remove_file(dir.join(file)).unwrap();
+    }
+
+    #[test]
+    fn test_interpolate_errors() {
+        let dir = env::temp_dir();
+        let variables = variables();
+        let mut secrets = Vec::new();
+
+        assert!(matches!(
+            interpolate("${MISSING}", &dir, &variables, &mut secrets),
+            Err(InterpolationError::MissingVariable(name)) if name == "MISSING"
+        ));
+        assert!(matches!(
+            interpolate(
+                "${file:rallyup-test-missing.secret}",
+                &dir,
+                &variables,
+                &mut secrets
+            ),
+            Err(InterpolationError::FileError(..))
+        ));
+        assert!(matches!(
+            interpolate("${not a variable}", &dir, &variables, &mut secrets),
+            Err(InterpolationError::InvalidReference(_))
+        ));
+        assert!(matches!(
+            interpolate("${secret:MISSING}", &dir, &variables, &mut secrets),
+            Err(InterpolationError::MissingVariable(name)) if name == "MISSING"
+        ));
+        assert!(matches!(
+            interpolate("${MISSING", &dir, &variables, &mut secrets),
+            Err(InterpolationError::Unterminated(_))
+        ));
+        assert!(secrets.is_empty());
+    }
+}
@@ -0,0 +1,1063 @@
+use aes::Aes128;
+use cbc::cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha1::Sha1;
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{timeout_at, Instant},
+};
+
+use thiserror::Error;
+
+// IPMI v2.0 over LAN (RMCP+), see sections 13 and 22 of the IPMI v2.0 specification
+//
+// Every packet starts with the RMCP header:
+// | Version (0x06) | Reserved | Sequence (0xFF) | Class (0x07, IPMI) |
+//
+// Followed by the RMCP+ session header and payload:
+// | Auth type (0x06) | Payload type | Session ID | Session sequence | Payload length | Payload |
+// | 1 byte           | 1 byte       | 4 bytes    | 4 bytes          | 2 bytes        | ...     |
+//
+// Authenticated packets end with a trailer, where the AuthCode covers everything from the auth
+// type up to the next header:
+// | Integrity pad (0xFF) | Pad length | Next header (0x07) | AuthCode |
+//
+// Only cipher suite 3 (RAKP-HMAC-SHA1, HMAC-SHA1-96, AES-CBC-128) is implemented, which is the
+// one every IPMI 2.0 BMC supports. All integers are little-endian.
+
+const RMCP_HEADER: [u8; 4] = [0x06, 0x00, 0xFF, 0x07];
+const AUTH_TYPE_NONE: u8 = 0x00;
+const AUTH_TYPE_RMCP_PLUS: u8 = 0x06;
+
+const PAYLOAD_IPMI: u8 = 0x00;
+const PAYLOAD_OPEN_SESSION_REQUEST: u8 = 0x10;
+const PAYLOAD_OPEN_SESSION_RESPONSE: u8 = 0x11;
+const PAYLOAD_RAKP_1: u8 = 0x12;
+const PAYLOAD_RAKP_2: u8 = 0x13;
+const PAYLOAD_RAKP_3: u8 = 0x14;
+const PAYLOAD_RAKP_4: u8 = 0x15;
+const PAYLOAD_ENCRYPTED: u8 = 0x80;
+const PAYLOAD_AUTHENTICATED: u8 = 0x40;
+const PAYLOAD_TYPE_MASK: u8 = 0x3F;
+
+const ALGORITHM_RAKP_HMAC_SHA1: u8 = 0x01;
+const ALGORITHM_HMAC_SHA1_96: u8 = 0x01;
+const ALGORITHM_AES_CBC_128: u8 = 0x01;
+
+// Look the user up by name only, instead of by name and privilege level
+const RAKP_NAME_ONLY_LOOKUP: u8 = 0x10;
+
+const BMC_ADDRESS: u8 = 0x20;
+const REMOTE_CONSOLE_ADDRESS: u8 = 0x81;
+
+const NETFN_CHASSIS: u8 = 0x00;
+const NETFN_APP: u8 = 0x06;
+
+const CMD_GET_CHASSIS_STATUS: u8 = 0x01;
+const CMD_CHASSIS_CONTROL: u8 = 0x02;
+const CMD_GET_CHANNEL_AUTH_CAPABILITIES: u8 = 0x38;
+const CMD_SET_SESSION_PRIVILEGE: u8 = 0x3B;
+const CMD_CLOSE_SESSION: u8 = 0x3C;
+
+const CHASSIS_POWER_UP: u8 = 0x01;
+const CHASSIS_SOFT_SHUTDOWN: u8 = 0x05;
+const CHASSIS_POWER_ON_BIT: u8 = 0x01;
+
+// Current channel, with the IPMI v2.0 extended data
+const CURRENT_CHANNEL_V2: u8 = 0x8E;
+const CAPABILITY_IPMI_V2: u8 = 0x02;
+
+const SIZE_SESSION_HEADER: usize = 12;
+const SIZE_RANDOM: usize = 16;
+const SIZE_GUID: usize = 16;
+const SIZE_HMAC_SHA1: usize = 20;
+const SIZE_AUTH_CODE: usize = 12;
+const SIZE_AES_BLOCK: usize = 16;
+const MAX_USERNAME: usize = 16;
+const MAX_PASSWORD: usize = 20;
+
+// BMCs drop requests while they are busy, so every request is retried a few times
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const REQUEST_ATTEMPTS: u32 = 3;
+
+fn default_ipmi_port() -> u16 {
+    623
+}
+
+#[derive(Debug, Error)]
+pub enum IpmiError {
+    #[error("Invalid IPMI configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("IPMI network error: {0}")]
+    NetworkError(#[from] io::Error),
+
+    #[error("No response from BMC at {0}")]
+    Timeout(String),
+
+    #[error("Malformed IPMI packet: {0}")]
+    MalformedPacket(String),
+
+    #[error("Failed to open IPMI session: {0}")]
+    SessionError(String),
+
+    #[error("IPMI authentication failed, check the username and password")]
+    AuthenticationFailed,
+
+    #[error("IPMI command {0:#04x} failed with completion code {1:#04x}")]
+    CommandFailed(u8, u8),
+}
+
+type Result<T> = std::result::Result<T, IpmiError>;
+
+/// Privilege level requested for the session, chassis control requires at least `operator`
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Privilege {
+    User,
+    Operator,
+    #[default]
+    Administrator,
+}
+
+impl Privilege {
+    fn level(self) -> u8 {
+        match self {
+            Privilege::User => 0x02,
+            Privilege::Operator => 0x03,
+            Privilege::Administrator => 0x04,
+        }
+    }
+}
+
+/// Connection to a BMC with IPMI over LAN
+#[derive(Debug, Deserialize, Clone)]
+pub struct IpmiConfig {
+    pub host: String,
+    #[serde(default = "default_ipmi_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub privilege: Privilege,
+}
+
+impl IpmiConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.host.is_empty() {
+            return Err(IpmiError::InvalidConfig("`host` is empty".into()));
+        }
+        if self.username.len() > MAX_USERNAME {
+            return Err(IpmiError::InvalidConfig(format!(
+                "username is longer than {} bytes",
+                MAX_USERNAME
+            )));
+        }
+        if self.password.len() > MAX_PASSWORD {
+            return Err(IpmiError::InvalidConfig(format!(
+                "password is longer than {} bytes",
+                MAX_PASSWORD
+            )));
+        }
+        Ok(())
+    }
+
+    fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn status_description(status: u8) -> String {
+    let description = match status {
+        0x01 => "insufficient resources to create a session",
+        0x02 => "invalid session ID",
+        0x03 => "invalid payload type",
+        0x04 => "invalid authentication algorithm",
+        0x05 => "invalid integrity algorithm",
+        0x06 => "no matching authentication payload",
+        0x07 => "no matching integrity payload",
+        0x08 => "inactive session ID",
+        0x09 => "invalid role",
+        0x0A => "unauthorized role or privilege level requested",
+        0x0B => "insufficient resources to create a session at the requested role",
+        0x0C => "invalid name length",
+        0x0D => "unauthorized name",
+        0x0E => "unauthorized GUID",
+        0x0F => "invalid integrity check value",
+        0x10 => "invalid confidentiality algorithm",
+        0x11 => "no cipher suite match with proposed security algorithms",
+        0x12 => "illegal or unrecognized parameter",
+        _ => "unknown status",
+    };
+    format!("{} ({:#04x})", description, status)
+}
+
+fn hmac_sha1(key: &[u8], parts: &[&[u8]]) -> [u8; SIZE_HMAC_SHA1] {
+    // Keys shorter than the block size are zero padded by HMAC itself, which is exactly how the
+    // spec pads passwords to 20 bytes
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)))
+}
+
+/// Keys derived from the session integrity key (SIK) once the RAKP handshake is done
+#[derive(Debug, Clone)]
+struct SessionKeys {
+    integrity: [u8; SIZE_HMAC_SHA1],
+    confidentiality: [u8; SIZE_AES_BLOCK],
+}
+
+impl SessionKeys {
+    fn derive(sik: &[u8]) -> Self {
+        let integrity = hmac_sha1(sik, &[&[0x01; SIZE_HMAC_SHA1]]);
+        let k2 = hmac_sha1(sik, &[&[0x02; SIZE_HMAC_SHA1]]);
+
+        let mut confidentiality = [0u8; SIZE_AES_BLOCK];
+        confidentiality.copy_from_slice(&k2[..SIZE_AES_BLOCK]);
+
+        SessionKeys {
+            integrity,
+            confidentiality,
+        }
+    }
+
+    /// IV followed by the AES-CBC-128 encrypted payload, padded with 1, 2, 3, ... and the pad length
+    fn encrypt(&self, payload: &[u8]) -> Vec<u8> {
+        let iv: [u8; SIZE_AES_BLOCK] = rand::random();
+
+        let pad_length = (SIZE_AES_BLOCK - (payload.len() + 1) % SIZE_AES_BLOCK) % SIZE_AES_BLOCK;
+        let mut plaintext = payload.to_vec();
+        plaintext.extend(1..=pad_length as u8);
+        plaintext.push(pad_length as u8);
+
+        let length = plaintext.len();
+        let ciphertext = cbc::Encryptor::<Aes128>::new(&self.confidentiality.into(), &iv.into())
+            .encrypt_padded_mut::<NoPadding>(&mut plaintext, length)
+            .expect("plaintext is padded to the block size");
+
+        let mut encrypted = iv.to_vec();
+        encrypted.extend_from_slice(ciphertext);
+        encrypted
+    }
+
+    fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.len() < 2 * SIZE_AES_BLOCK || !encrypted.len().is_multiple_of(SIZE_AES_BLOCK) {
+            return Err(IpmiError::MalformedPacket(
+                "encrypted payload is not a multiple of the AES block size".into(),
+            ));
+        }
+
+        let (iv, ciphertext) = encrypted.split_at(SIZE_AES_BLOCK);
+        let mut iv_block = [0u8; SIZE_AES_BLOCK];
+        iv_block.copy_from_slice(iv);
+
+        let mut buffer = ciphertext.to_vec();
+        let plaintext =
+            cbc::Decryptor::<Aes128>::new(&self.confidentiality.into(), &iv_block.into())
+                .decrypt_padded_mut::<NoPadding>(&mut buffer)
+                .map_err(|_| IpmiError::MalformedPacket("failed to decrypt payload".into()))?;
+
+        let pad_length = *plaintext.last().unwrap_or(&0) as usize;
+        if pad_length >= plaintext.len() {
+            return Err(IpmiError::MalformedPacket(
+                "invalid confidentiality pad".into(),
+            ));
+        }
+        Ok(plaintext[..plaintext.len() - 1 - pad_length].to_vec())
+    }
+}
+
+/// An RMCP+ packet without the RMCP header and session trailer
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SessionPacket {
+    payload_type: u8,
+    session_id: u32,
+    sequence: u32,
+    payload: Vec<u8>,
+}
+
+impl SessionPacket {
+    fn new(payload_type: u8, payload: Vec<u8>) -> Self {
+        SessionPacket {
+            payload_type,
+            session_id: 0,
+            sequence: 0,
+            payload,
+        }
+    }
+
+    fn encode(&self, integrity_key: Option<&[u8; SIZE_HMAC_SHA1]>) -> Vec<u8> {
+        let mut packet = RMCP_HEADER.to_vec();
+        packet.push(AUTH_TYPE_RMCP_PLUS);
+        packet.push(self.payload_type);
+        packet.extend_from_slice(&self.session_id.to_le_bytes());
+        packet.extend_from_slice(&self.sequence.to_le_bytes());
+        packet.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        packet.extend_from_slice(&self.payload);
+
+        if self.payload_type & PAYLOAD_AUTHENTICATED != 0 {
+            let key = integrity_key.expect("authenticated packets need an integrity key");
+
+            // Pad length and next header count towards the 4 byte alignment
+            let pad_length = (4 - (packet.len() - RMCP_HEADER.len() + 2) % 4) % 4;
+            packet.extend(std::iter::repeat_n(0xFF, pad_length));
+            packet.push(pad_length as u8);
+            packet.push(RMCP_HEADER[3]);
+
+            let auth_code = hmac_sha1(key, &[&packet[RMCP_HEADER.len()..]]);
+            packet.extend_from_slice(&auth_code[..SIZE_AUTH_CODE]);
+        }
+
+        packet
+    }
+
+    fn decode(packet: &[u8], integrity_key: Option<&[u8; SIZE_HMAC_SHA1]>) -> Result<Self> {
+        let header_end = RMCP_HEADER.len() + SIZE_SESSION_HEADER;
+        if packet.len() < header_end || packet[..4] != RMCP_HEADER {
+            return Err(IpmiError::MalformedPacket("not an RMCP packet".into()));
+        }
+        if packet[4] != AUTH_TYPE_RMCP_PLUS {
+            return Err(IpmiError::MalformedPacket(format!(
+                "unexpected auth type {:#04x}",
+                packet[4]
+            )));
+        }
+
+        let payload_type = packet[5];
+        let session_id = u32::from_le_bytes(packet[6..10].try_into().unwrap());
+        let sequence = u32::from_le_bytes(packet[10..14].try_into().unwrap());
+        let length = u16::from_le_bytes(packet[14..16].try_into().unwrap()) as usize;
+
+        if packet.len() < header_end + length {
+            return Err(IpmiError::MalformedPacket("truncated payload".into()));
+        }
+        let payload = packet[header_end..header_end + length].to_vec();
+
+        if payload_type & PAYLOAD_AUTHENTICATED != 0 {
+            let key = integrity_key.ok_or_else(|| {
+                IpmiError::MalformedPacket("authenticated packet outside of a session".into())
+            })?;
+            if packet.len() < header_end + length + 2 + SIZE_AUTH_CODE {
+                return Err(IpmiError::MalformedPacket(
+                    "truncated session trailer".into(),
+                ));
+            }
+
+            let (covered, auth_code) = packet.split_at(packet.len() - SIZE_AUTH_CODE);
+            let expected = hmac_sha1(key, &[&covered[RMCP_HEADER.len()..]]);
+            if expected[..SIZE_AUTH_CODE] != *auth_code {
+                return Err(IpmiError::MalformedPacket("integrity check failed".into()));
+            }
+        }
+
+        Ok(SessionPacket {
+            payload_type,
+            session_id,
+            sequence,
+            payload,
+        })
+    }
+}
+
+/// IPMI v1.5 packet outside of a session, only used to query the authentication capabilities
+fn encode_sessionless(message: &[u8]) -> Vec<u8> {
+    let mut packet = RMCP_HEADER.to_vec();
+    packet.push(AUTH_TYPE_NONE);
+    // Session sequence and ID
+    packet.extend_from_slice(&[0; 8]);
+    packet.push(message.len() as u8);
+    packet.extend_from_slice(message);
+    packet
+}
+
+fn decode_sessionless(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 14 || packet[..4] != RMCP_HEADER || packet[4] != AUTH_TYPE_NONE {
+        return None;
+    }
+    let length = packet[13] as usize;
+    packet.get(14..14 + length)
+}
+
+/// IPMI request message:
+/// | rsAddr | netFn/rsLUN | Checksum | rqAddr | rqSeq/rqLUN | Command | Data | Checksum |
+fn encode_request(net_fn: u8, rq_sequence: u8, command: u8, data: &[u8]) -> Vec<u8> {
+    let mut message = vec![BMC_ADDRESS, net_fn << 2];
+    message.push(checksum(&message));
+    message.extend_from_slice(&[REMOTE_CONSOLE_ADDRESS, rq_sequence << 2, command]);
+    message.extend_from_slice(data);
+    message.push(checksum(&message[3..]));
+    message
+}
+
+/// Data of the response to the given request, or `None` if the message answers something else.
+///
+/// IPMI response message:
+/// | rqAddr | netFn/rqLUN | Checksum | rsAddr | rqSeq/rsLUN | Command | Completion code | Data | Checksum |
+fn decode_response(
+    message: &[u8],
+    net_fn: u8,
+    rq_sequence: u8,
+    command: u8,
+) -> Option<Result<Vec<u8>>> {
+    if message.len() < 8 {
+        return Some(Err(IpmiError::MalformedPacket(
+            "IPMI response is too short".into(),
+        )));
+    }
+    if message[1] >> 2 != net_fn | 1 || message[4] >> 2 != rq_sequence || message[5] != command {
+        return None;
+    }
+    let last = message.len() - 1;
+    if checksum(&message[..2]) != message[2] || checksum(&message[3..last]) != message[last] {
+        return Some(Err(IpmiError::MalformedPacket(
+            "IPMI response checksum mismatch".into(),
+        )));
+    }
+
+    let completion_code = message[6];
+    if completion_code != 0 {
+        return Some(Err(IpmiError::CommandFailed(command, completion_code)));
+    }
+    Some(Ok(message[7..last].to_vec()))
+}
+
+/// An authenticated and encrypted RMCP+ session with a BMC
+struct Session {
+    socket: UdpSocket,
+    address: String,
+    console_id: u32,
+    managed_id: u32,
+    sequence: u32,
+    rq_sequence: u8,
+    keys: SessionKeys,
+}
+
+impl Session {
+    async fn connect(config: &IpmiConfig) -> Result<UdpSocket> {
+        let remote = lookup_host((config.host.as_str(), config.port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                IpmiError::InvalidConfig(format!("failed to resolve {}", config.host))
+            })?;
+        let local: SocketAddr = match remote {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(remote).await?;
+        Ok(socket)
+    }
+
+    /// Send `request` until `parse` accepts one of the datagrams that come back
+    async fn exchange<T>(
+        socket: &UdpSocket,
+        address: &str,
+        request: &[u8],
+        mut parse: impl FnMut(&[u8]) -> Option<Result<T>>,
+    ) -> Result<T> {
+        let mut buffer = [0u8; 1024];
+
+        for _ in 0..REQUEST_ATTEMPTS {
+            socket.send(request).await?;
+
+            let deadline = Instant::now() + REQUEST_TIMEOUT;
+            while let Ok(received) = timeout_at(deadline, socket.recv(&mut buffer)).await {
+                if let Some(result) = parse(&buffer[..received?]) {
+                    return result;
+                }
+            }
+        }
+
+        Err(IpmiError::Timeout(address.to_string()))
+    }
+
+    /// Exchange a session setup message and return the payload of the response
+    async fn setup_exchange(
+        socket: &UdpSocket,
+        address: &str,
+        request: SessionPacket,
+        response_type: u8,
+    ) -> Result<Vec<u8>> {
+        let payload = Self::exchange(socket, address, &request.encode(None), |packet| {
+            match SessionPacket::decode(packet, None) {
+                Ok(response) if response.payload_type == response_type => {
+                    Some(Ok(response.payload))
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })
+        .await?;
+
+        // Every setup response starts with the message tag and a status code
+        match payload.get(1) {
+            None => Err(IpmiError::MalformedPacket(
+                "empty session setup response".into(),
+            )),
+            Some(0) => Ok(payload),
+            Some(status) => Err(IpmiError::SessionError(status_description(*status))),
+        }
+    }
+
+    async fn check_capabilities(socket: &UdpSocket, address: &str, privilege: u8) -> Result<()> {
+        let request = encode_request(
+            NETFN_APP,
+            0,
+            CMD_GET_CHANNEL_AUTH_CAPABILITIES,
+            &[CURRENT_CHANNEL_V2, privilege],
+        );
+
+        let capabilities =
+            Self::exchange(socket, address, &encode_sessionless(&request), |packet| {
+                decode_sessionless(packet).and_then(|message| {
+                    decode_response(message, NETFN_APP, 0, CMD_GET_CHANNEL_AUTH_CAPABILITIES)
+                })
+            })
+            .await?;
+
+        match capabilities.get(3) {
+            Some(extended) if extended & CAPABILITY_IPMI_V2 != 0 => Ok(()),
+            _ => Err(IpmiError::SessionError(
+                "BMC does not support IPMI v2.0 (RMCP+)".into(),
+            )),
+        }
+    }
+
+    /// Open a session with the RAKP handshake, see section 13.20 of the spec
+    async fn open(config: &IpmiConfig) -> Result<Self> {
+        config.validate()?;
+
+        let address = config.address();
+        let socket = Self::connect(config).await?;
+        let privilege = config.privilege.level();
+
+        Self::check_capabilities(&socket, &address, privilege).await?;
+
+        // Open session request
+        let console_id = rand::random::<u32>().max(1);
+        let mut request = vec![0x00, privilege, 0x00, 0x00];
+        request.extend_from_slice(&console_id.to_le_bytes());
+        for (payload, algorithm) in [
+            (0x00, ALGORITHM_RAKP_HMAC_SHA1),
+            (0x01, ALGORITHM_HMAC_SHA1_96),
+            (0x02, ALGORITHM_AES_CBC_128),
+        ] {
+            request.extend_from_slice(&[payload, 0x00, 0x00, 0x08, algorithm, 0x00, 0x00, 0x00]);
+        }
+        let response = Self::setup_exchange(
+            &socket,
+            &address,
+            SessionPacket::new(PAYLOAD_OPEN_SESSION_REQUEST, request),
+            PAYLOAD_OPEN_SESSION_RESPONSE,
+        )
+        .await?;
+        if response.len() < 12 || response[4..8] != console_id.to_le_bytes() {
+            return Err(IpmiError::MalformedPacket(
+                "invalid open session response".into(),
+            ));
+        }
+        let managed_id = u32::from_le_bytes(response[8..12].try_into().unwrap());
+
+        // RAKP 1 and 2
+        let console_random: [u8; SIZE_RANDOM] = rand::random();
+        let role = RAKP_NAME_ONLY_LOOKUP | privilege;
+        let username = config.username.as_bytes();
+        let password = config.password.as_bytes();
+        let user = [&[role, username.len() as u8][..], username].concat();
+
+        let mut request = vec![0x00, 0x00, 0x00, 0x00];
+        request.extend_from_slice(&managed_id.to_le_bytes());
+        request.extend_from_slice(&console_random);
+        request.extend_from_slice(&[role, 0x00, 0x00, username.len() as u8]);
+        request.extend_from_slice(username);
+        let response = Self::setup_exchange(
+            &socket,
+            &address,
+            SessionPacket::new(PAYLOAD_RAKP_1, request),
+            PAYLOAD_RAKP_2,
+        )
+        .await?;
+        if response.len() < 40 + SIZE_HMAC_SHA1 || response[4..8] != console_id.to_le_bytes() {
+            return Err(IpmiError::MalformedPacket("invalid RAKP message 2".into()));
+        }
+        let managed_random = &response[8..8 + SIZE_RANDOM];
+        let managed_guid = &response[24..24 + SIZE_GUID];
+
+        let expected = hmac_sha1(
+            password,
+            &[
+                &console_id.to_le_bytes(),
+                &managed_id.to_le_bytes(),
+                &console_random,
+                managed_random,
+                managed_guid,
+                &user,
+            ],
+        );
+        if response[40..40 + SIZE_HMAC_SHA1] != expected {
+            return Err(IpmiError::AuthenticationFailed);
+        }
+
+        // RAKP 3 and 4
+        let mut request = vec![0x00, 0x00, 0x00, 0x00];
+        request.extend_from_slice(&managed_id.to_le_bytes());
+        request.extend_from_slice(&hmac_sha1(
+            password,
+            &[managed_random, &console_id.to_le_bytes(), &user],
+        ));
+        let response = Self::setup_exchange(
+            &socket,
+            &address,
+            SessionPacket::new(PAYLOAD_RAKP_3, request),
+            PAYLOAD_RAKP_4,
+        )
+        .await?;
+
+        let sik = hmac_sha1(password, &[&console_random, managed_random, &user]);
+        let expected = hmac_sha1(
+            &sik,
+            &[&console_random, &managed_id.to_le_bytes(), managed_guid],
+        );
+        if response.len() < 8 + SIZE_AUTH_CODE
+            || response[8..8 + SIZE_AUTH_CODE] != expected[..SIZE_AUTH_CODE]
+        {
+            return Err(IpmiError::AuthenticationFailed);
+        }
+
+        let mut session = Session {
+            socket,
+            address,
+            console_id,
+            managed_id,
+            sequence: 0,
+            rq_sequence: 0,
+            keys: SessionKeys::derive(&sik),
+        };
+        session
+            .command(NETFN_APP, CMD_SET_SESSION_PRIVILEGE, &[privilege])
+            .await?;
+
+        Ok(session)
+    }
+
+    async fn command(&mut self, net_fn: u8, command: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.sequence = self.sequence.wrapping_add(1).max(1);
+        self.rq_sequence = (self.rq_sequence + 1) & 0x3F;
+        let rq_sequence = self.rq_sequence;
+
+        let message = encode_request(net_fn, rq_sequence, command, data);
+        let packet = SessionPacket {
+            payload_type: PAYLOAD_ENCRYPTED | PAYLOAD_AUTHENTICATED | PAYLOAD_IPMI,
+            session_id: self.managed_id,
+            sequence: self.sequence,
+            payload: self.keys.encrypt(&message),
+        }
+        .encode(Some(&self.keys.integrity));
+
+        let keys = &self.keys;
+        let console_id = self.console_id;
+        Self::exchange(&self.socket, &self.address, &packet, |packet| {
+            let response = match SessionPacket::decode(packet, Some(&keys.integrity)) {
+                Ok(response) => response,
+                Err(e) => return Some(Err(e)),
+            };
+            if response.session_id != console_id
+                || response.payload_type & PAYLOAD_TYPE_MASK != PAYLOAD_IPMI
+            {
+                return None;
+            }
+            if response.payload_type & PAYLOAD_ENCRYPTED == 0 {
+                return Some(Err(IpmiError::MalformedPacket(
+                    "unencrypted response in an encrypted session".into(),
+                )));
+            }
+            match keys.decrypt(&response.payload) {
+                Ok(message) => decode_response(&message, net_fn, rq_sequence, command),
+                Err(e) => Some(Err(e)),
+            }
+        })
+        .await
+    }
+
+    /// Sessions time out on their own, so failing to close one is not an error
+    async fn close(mut self) {
+        let managed_id = self.managed_id.to_le_bytes();
+        let _ = self
+            .command(NETFN_APP, CMD_CLOSE_SESSION, &managed_id)
+            .await;
+    }
+}
+
+async fn chassis_control(config: &IpmiConfig, action: u8) -> Result<()> {
+    let mut session = Session::open(config).await?;
+    let result = session
+        .command(NETFN_CHASSIS, CMD_CHASSIS_CONTROL, &[action])
+        .await;
+    session.close().await;
+    result.map(|_| ())
+}
+
+pub async fn power_on(config: &IpmiConfig) -> Result<()> {
+    chassis_control(config, CHASSIS_POWER_UP).await
+}
+
+/// Ask the operating system to shut down with an emulated ACPI power button press
+pub async fn power_off(config: &IpmiConfig) -> Result<()> {
+    chassis_control(config, CHASSIS_SOFT_SHUTDOWN).await
+}
+
+/// Whether the BMC reports the chassis as powered on
+pub async fn is_powered_on(config: &IpmiConfig) -> Result<bool> {
+    let mut session = Session::open(config).await?;
+    let result = session
+        .command(NETFN_CHASSIS, CMD_GET_CHASSIS_STATUS, &[])
+        .await;
+    session.close().await;
+
+    match result?.first() {
+        Some(power_state) => Ok(power_state & CHASSIS_POWER_ON_BIT != 0),
+        None => Err(IpmiError::MalformedPacket(
+            "empty chassis status response".into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    const MANAGED_ID: u32 = 0x0A0B_0C0D;
+    const MANAGED_RANDOM: [u8; SIZE_RANDOM] = [0x24; SIZE_RANDOM];
+    const MANAGED_GUID: [u8; SIZE_GUID] = [0x42; SIZE_GUID];
+
+    #[derive(Debug, Default)]
+    struct BmcState {
+        powered_on: AtomicBool,
+        commands: Mutex<Vec<u8>>,
+    }
+
+    /// Answer an IPMI request message
+    fn response(request: &[u8], completion_code: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![request[3], ((request[1] >> 2) | 1) << 2];
+        message.push(checksum(&message));
+        message.extend_from_slice(&[request[0], request[4], request[5], completion_code]);
+        message.extend_from_slice(data);
+        message.push(checksum(&message[3..]));
+        message
+    }
+
+    /// BMC simulator with a single user, speaking just enough RMCP+ for the chassis commands
+    async fn simulator(username: &str, password: &str) -> (IpmiConfig, Arc<BmcState>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let config = IpmiConfig {
+            host: "127.0.0.1".into(),
+            port: socket.local_addr().unwrap().port(),
+            username: username.into(),
+            password: password.into(),
+            privilege: Privilege::default(),
+        };
+
+        let state = Arc::new(BmcState::default());
+        let bmc = state.clone();
+        let (username, password) = (username.as_bytes().to_vec(), password.as_bytes().to_vec());
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let mut console_id = 0u32;
+            let mut console_random = Vec::new();
+            let mut user = Vec::new();
+            let mut keys: Option<SessionKeys> = None;
+
+            loop {
+                let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let packet = &buffer[..length];
+
+                if let Some(request) = decode_sessionless(packet) {
+                    assert_eq!(CMD_GET_CHANNEL_AUTH_CAPABILITIES, request[5]);
+                    let reply = response(request, 0, &[0x01, 0x80, 0x04, 0x02, 0, 0, 0, 0]);
+                    socket
+                        .send_to(&encode_sessionless(&reply), peer)
+                        .await
+                        .unwrap();
+                    continue;
+                }
+
+                let request =
+                    SessionPacket::decode(packet, keys.as_ref().map(|k| &k.integrity)).unwrap();
+                let payload = &request.payload;
+
+                let reply = match request.payload_type & PAYLOAD_TYPE_MASK {
+                    PAYLOAD_OPEN_SESSION_REQUEST => {
+                        console_id = u32::from_le_bytes(payload[4..8].try_into().unwrap());
+                        let mut reply = vec![payload[0], 0x00, payload[1], 0x00];
+                        reply.extend_from_slice(&console_id.to_le_bytes());
+                        reply.extend_from_slice(&MANAGED_ID.to_le_bytes());
+                        reply.extend_from_slice(&payload[8..32]);
+                        SessionPacket::new(PAYLOAD_OPEN_SESSION_RESPONSE, reply)
+                    }
+                    PAYLOAD_RAKP_1 => {
+                        console_random = payload[8..24].to_vec();
+                        let name_length = payload[27] as usize;
+                        user = [&payload[24..25], &payload[27..28 + name_length]].concat();
+
+                        let status = if payload[28..28 + name_length] == username {
+                            0x00
+                        } else {
+                            0x0D
+                        };
+                        let mut reply = vec![payload[0], status, 0x00, 0x00];
+                        reply.extend_from_slice(&console_id.to_le_bytes());
+                        reply.extend_from_slice(&MANAGED_RANDOM);
+                        reply.extend_from_slice(&MANAGED_GUID);
+                        reply.extend_from_slice(&hmac_sha1(
+                            &password,
+                            &[
+                                &console_id.to_le_bytes(),
+                                &MANAGED_ID.to_le_bytes(),
+                                &console_random,
+                                &MANAGED_RANDOM,
+                                &MANAGED_GUID,
+                                &user,
+                            ],
+                        ));
+                        SessionPacket::new(PAYLOAD_RAKP_2, reply)
+                    }
+                    PAYLOAD_RAKP_3 => {
+                        let expected = hmac_sha1(
+                            &password,
+                            &[&MANAGED_RANDOM, &console_id.to_le_bytes(), &user],
+                        );
+                        let status = if payload[8..] == expected { 0x00 } else { 0x0F };
+
+                        let sik = hmac_sha1(&password, &[&console_random, &MANAGED_RANDOM, &user]);
+                        let icv = hmac_sha1(
+                            &sik,
+                            &[&console_random, &MANAGED_ID.to_le_bytes(), &MANAGED_GUID],
+                        );
+                        keys = Some(SessionKeys::derive(&sik));
+
+                        let mut reply = vec![payload[0], status, 0x00, 0x00];
+                        reply.extend_from_slice(&console_id.to_le_bytes());
+                        reply.extend_from_slice(&icv[..SIZE_AUTH_CODE]);
+                        SessionPacket::new(PAYLOAD_RAKP_4, reply)
+                    }
+                    PAYLOAD_IPMI => {
+                        assert_eq!(MANAGED_ID, request.session_id);
+                        assert_eq!(
+                            PAYLOAD_ENCRYPTED | PAYLOAD_AUTHENTICATED,
+                            request.payload_type & !PAYLOAD_TYPE_MASK
+                        );
+                        let keys = keys.as_ref().expect("IPMI message outside of a session");
+                        let message = keys.decrypt(payload).unwrap();
+                        let (net_fn, command) = (message[1] >> 2, message[5]);
+                        bmc.commands.lock().unwrap().push(command);
+
+                        let data = match (net_fn, command) {
+                            (NETFN_APP, CMD_SET_SESSION_PRIVILEGE) => vec![message[6]],
+                            (NETFN_APP, CMD_CLOSE_SESSION) => vec![],
+                            (NETFN_CHASSIS, CMD_GET_CHASSIS_STATUS) => {
+                                vec![bmc.powered_on.load(Ordering::SeqCst) as u8, 0x00, 0x00]
+                            }
+                            (NETFN_CHASSIS, CMD_CHASSIS_CONTROL) => {
+                                bmc.powered_on
+                                    .store(message[6] == CHASSIS_POWER_UP, Ordering::SeqCst);
+                                vec![]
+                            }
+                            _ => panic!("unexpected command {:#04x}", command),
+                        };
+                        SessionPacket {
+                            payload_type: request.payload_type,
+                            session_id: console_id,
+                            sequence: request.sequence,
+                            payload: keys.encrypt(&response(&message, 0, &data)),
+                        }
+                    }
+                    other => panic!("unexpected payload type {:#04x}", other),
+                };
+
+                let reply = reply.encode(keys.as_ref().map(|k| &k.integrity));
+                socket.send_to(&reply, peer).await.unwrap();
+            }
+        });
+
+        (config, state)
+    }
+
+    #[tokio::test]
+    async fn test_power_on() {
+        let (config, bmc) = simulator("admin", "secret").await;
+
+        power_on(&config).await.expect("failed to power on");
+
+        assert!(bmc.powered_on.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![
+                CMD_SET_SESSION_PRIVILEGE,
+                CMD_CHASSIS_CONTROL,
+                CMD_CLOSE_SESSION
+            ],
+            *bmc.commands.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_power_off() {
+        let (config, bmc) = simulator("admin", "secret").await;
+        bmc.powered_on.store(true, Ordering::SeqCst);
+
+        power_off(&config).await.expect("failed to power off");
+
+        assert!(!bmc.powered_on.load(Ordering::SeqCst));
+        assert!(bmc.commands.lock().unwrap().contains(&CMD_CHASSIS_CONTROL));
+    }
+
+    #[tokio::test]
+    async fn test_chassis_status() {
+        let (config, bmc) = simulator("admin", "secret").await;
+
+        assert!(!is_powered_on(&config).await.unwrap());
+        bmc.powered_on.store(true, Ordering::SeqCst);
+        assert!(is_powered_on(&config).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_authentication_failure() {
+        let (mut config, bmc) = simulator("admin", "secret").await;
+
+        config.password = "wrong".into();
+        assert!(matches!(
+            power_on(&config).await,
+            Err(IpmiError::AuthenticationFailed)
+        ));
+
+        config.username = "nobody".into();
+        assert!(matches!(
+            power_on(&config).await,
+            Err(IpmiError::SessionError(status)) if status.contains("unauthorized name")
+        ));
+
+        assert!(!bmc.powered_on.load(Ordering::SeqCst));
+        assert!(bmc.commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_packet() {
+        let key = [0x5A; SIZE_HMAC_SHA1];
+
+        for length in 0..8 {
+            let packet = SessionPacket {
+                payload_type: PAYLOAD_AUTHENTICATED | PAYLOAD_IPMI,
+                session_id: 0x1234_5678,
+                sequence: 7,
+                payload: vec![0xAB; length],
+            };
+            let encoded = packet.encode(Some(&key));
+
+            // The range covered by the AuthCode is aligned to 4 bytes
+            assert_eq!(0, (encoded.len() - RMCP_HEADER.len() - SIZE_AUTH_CODE) % 4);
+            assert_eq!(
+                packet,
+                SessionPacket::decode(&encoded, Some(&key)).expect("failed to decode packet")
+            );
+
+            let mut tampered = encoded.clone();
+            tampered[RMCP_HEADER.len() + 2] ^= 0x01;
+            assert!(matches!(
+                SessionPacket::decode(&tampered, Some(&key)),
+                Err(IpmiError::MalformedPacket(_))
+            ));
+        }
+
+        let packet = SessionPacket::new(PAYLOAD_OPEN_SESSION_REQUEST, vec![0x01, 0x02]);
+        let encoded = packet.encode(None);
+        assert_eq!(
+            vec![
+                0x06, 0x00, 0xFF, 0x07, 0x06, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0x02, 0x00, 0x01, 0x02
+            ],
+            encoded
+        );
+        assert_eq!(packet, SessionPacket::decode(&encoded, None).unwrap());
+    }
+
+    #[test]
+    fn test_encryption() {
+        let keys = SessionKeys::derive(&[0x11; SIZE_HMAC_SHA1]);
+
+        for length in 0..40 {
+            let payload: Vec<u8> = (0..length as u8).collect();
+            let encrypted = keys.encrypt(&payload);
+
+            assert_eq!(0, encrypted.len() % SIZE_AES_BLOCK);
+            assert!(encrypted.len() > SIZE_AES_BLOCK + length);
+            assert_eq!(payload, keys.decrypt(&encrypted).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_request_message() {
+        let request = encode_request(NETFN_CHASSIS, 5, CMD_CHASSIS_CONTROL, &[CHASSIS_POWER_UP]);
+        assert_eq!(
+            vec![0x20, 0x00, 0xE0, 0x81, 0x14, 0x02, 0x01, 0x68],
+            request
+        );
+
+        let reply = response(&request, 0, &[0xAA]);
+        assert_eq!(
+            Some(vec![0xAA]),
+            decode_response(&reply, NETFN_CHASSIS, 5, CMD_CHASSIS_CONTROL)
+                .map(|data| data.unwrap())
+        );
+        // Response to some other request
+        assert!(decode_response(&reply, NETFN_CHASSIS, 6, CMD_CHASSIS_CONTROL).is_none());
+
+        let reply = response(&request, 0xC1, &[]);
+        assert!(matches!(
+            decode_response(&reply, NETFN_CHASSIS, 5, CMD_CHASSIS_CONTROL),
+            Some(Err(IpmiError::CommandFailed(CMD_CHASSIS_CONTROL, 0xC1)))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = IpmiConfig {
+            host: "10.0.0.5".into(),
+            port: default_ipmi_port(),
+            username: "admin".into(),
+            password: "secret".into(),
+            privilege: Privilege::Operator,
+        };
+        assert!(config.validate().is_ok());
+
+        let mut long_username = config.clone();
+        long_username.username = "a".repeat(17);
+        assert!(matches!(
+            long_username.validate(),
+            Err(IpmiError::InvalidConfig(_))
+        ));
+
+        let mut long_password = config.clone();
+        long_password.password = "a".repeat(21);
+        assert!(matches!(
+            long_password.validate(),
+            Err(IpmiError::InvalidConfig(_))
+        ));
+    }
+}
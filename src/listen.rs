@@ -0,0 +1,440 @@
+use colored::Colorize;
+use pnet::datalink::{self, Channel::Ethernet};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::SystemTime;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use thiserror::Error;
+
+use crate::interfaces;
+use crate::servers::Server;
+
+const SIZE_MAC: usize = 6;
+const SIZE_SYNC_STREAM: usize = 6;
+const MAC_REPETITIONS: usize = 16;
+const SIZE_VLAN_TAG: usize = 2;
+const SIZE_VLAN_HEADER: usize = 4;
+
+/// Ports magic packets are conventionally sent to over UDP (echo and discard)
+const WOL_UDP_PORTS: [u16; 2] = [7, 9];
+
+#[derive(Debug, Error)]
+pub enum ListenError {
+    #[error("Failed to find network interface: {0}")]
+    InterfaceNotFound(String),
+
+    #[error("Network error: {0}")]
+    NetworkError(#[from] std::io::Error),
+
+    #[error("Nothing to listen on, give an interface and/or enable UDP")]
+    NothingToListenOn,
+}
+
+type Result<T> = std::result::Result<T, ListenError>;
+
+/// A magic packet found in a frame or datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagicPacket {
+    pub target: MacAddr,
+    pub password: Option<Vec<u8>>,
+}
+
+/// Where a magic packet was seen and how it got there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub source: String,
+    /// VLAN IDs from the outermost to the innermost tag
+    pub vlans: Vec<u16>,
+    /// `ethernet` for EtherType 0x0842 frames, `udp` for datagrams
+    pub via: &'static str,
+    pub packet: MagicPacket,
+}
+
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.via, self.source, self.packet.target)?;
+        if !self.vlans.is_empty() {
+            let vlans: Vec<String> = self.vlans.iter().map(|id| id.to_string()).collect();
+            write!(f, " vlan {}", vlans.join("/"))?;
+        }
+        if let Some(password) = &self.packet.password {
+            write!(f, " password {}", format_bytes(password))?;
+        }
+        Ok(())
+    }
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Find a magic packet (6 bytes of FF followed by the same MAC 16 times) anywhere in `payload`.
+///
+/// 4 or 6 bytes directly following the last repetition are taken as the SecureOn password.
+/// Anything else after the packet (e.g. Ethernet padding) is ignored.
+pub fn decode_magic_packet(payload: &[u8]) -> Option<MagicPacket> {
+    let size_packet = SIZE_SYNC_STREAM + MAC_REPETITIONS * SIZE_MAC;
+
+    (0..payload.len().checked_sub(size_packet - 1)?).find_map(|start| {
+        let candidate = &payload[start..start + size_packet];
+        if candidate[..SIZE_SYNC_STREAM] != [0xFF; SIZE_SYNC_STREAM] {
+            return None;
+        }
+
+        let target = &candidate[SIZE_SYNC_STREAM..SIZE_SYNC_STREAM + SIZE_MAC];
+        if candidate[SIZE_SYNC_STREAM..]
+            .chunks(SIZE_MAC)
+            .any(|chunk| chunk != target)
+        {
+            return None;
+        }
+
+        let rest = &payload[start + size_packet..];
+        let password = match rest.len() {
+            4 | 6 => Some(rest.to_vec()),
+            _ => None,
+        };
+
+        Some(MagicPacket {
+            target: MacAddr::new(
+                target[0], target[1], target[2], target[3], target[4], target[5],
+            ),
+            password,
+        })
+    })
+}
+
+fn decode_udp(udp: &UdpPacket, source: IpAddr) -> Option<(String, MagicPacket)> {
+    let packet = decode_magic_packet(udp.payload())?;
+    Some((
+        SocketAddr::new(source, udp.get_source()).to_string(),
+        packet,
+    ))
+}
+
+/// Decode a raw Ethernet frame, looking through any number of VLAN tags, for a magic packet
+/// sent either with EtherType 0x0842 or inside an IPv4/IPv6 UDP datagram.
+pub fn decode_frame(frame: &[u8]) -> Option<Capture> {
+    let ethernet = EthernetPacket::new(frame)?;
+
+    let mut ethertype = ethernet.get_ethertype();
+    let mut payload = ethernet.payload();
+    let mut vlans = Vec::new();
+
+    while matches!(
+        ethertype,
+        EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ
+    ) {
+        if payload.len() < SIZE_VLAN_HEADER {
+            return None;
+        }
+        let tci = u16::from_be_bytes([payload[0], payload[1]]);
+        vlans.push(tci & 0x0FFF);
+        ethertype = EtherType(u16::from_be_bytes([
+            payload[SIZE_VLAN_TAG],
+            payload[SIZE_VLAN_TAG + 1],
+        ]));
+        payload = &payload[SIZE_VLAN_HEADER..];
+    }
+
+    let (source, via, packet) = match ethertype {
+        EtherTypes::WakeOnLan => (
+            ethernet.get_source().to_string(),
+            "ethernet",
+            decode_magic_packet(payload)?,
+        ),
+        EtherTypes::Ipv4 => {
+            let ip = Ipv4Packet::new(payload)?;
+            if ip.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+                return None;
+            }
+            let (source, packet) =
+                decode_udp(&UdpPacket::new(ip.payload())?, IpAddr::V4(ip.get_source()))?;
+            (source, "udp", packet)
+        }
+        EtherTypes::Ipv6 => {
+            let ip = Ipv6Packet::new(payload)?;
+            if ip.get_next_header() != IpNextHeaderProtocols::Udp {
+                return None;
+            }
+            let (source, packet) =
+                decode_udp(&UdpPacket::new(ip.payload())?, IpAddr::V6(ip.get_source()))?;
+            (source, "udp", packet)
+        }
+        _ => return None,
+    };
+
+    Some(Capture {
+        source,
+        vlans,
+        via,
+        packet,
+    })
+}
+
+fn report(capture: &Capture, servers: &[Server]) {
+    let server = servers
+        .iter()
+        .find(|server| server.mac.map(|mac| mac.addr()) == Some(capture.packet.target));
+
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+    let matched = match server {
+        Some(server) => {
+            let expected = server.password.as_ref().map(|p| p.bytes().to_vec());
+            if expected.is_some() && expected != capture.packet.password {
+                format!("{} {}", server.name.bold(), "(password mismatch)".red())
+            } else {
+                server.name.bold().green().to_string()
+            }
+        }
+        None => "unknown server".yellow().to_string(),
+    };
+
+    println!("[{}] {} {}", timestamp, capture, matched);
+}
+
+fn listen_ethernet(interface_name: &str, captures: mpsc::UnboundedSender<Capture>) -> Result<()> {
+    let interface = interfaces::find_interface(interface_name)
+        .ok_or_else(|| ListenError::InterfaceNotFound(interface_name.to_string()))?;
+
+    let mut rx = match datalink::channel(&interface, Default::default()) {
+        Ok(Ethernet(_, rx)) => rx,
+        Ok(_) => {
+            return Err(ListenError::NetworkError(std::io::Error::other(
+                "unhandled channel type for this interface",
+            )))
+        }
+        Err(e) => return Err(ListenError::NetworkError(e)),
+    };
+
+    // The datalink receiver blocks, so keep it off the async runtime
+    std::thread::spawn(move || {
+        while let Ok(frame) = rx.next() {
+            if let Some(capture) = decode_frame(frame) {
+                if captures.send(capture).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Bind a UDP socket, IPv6 sockets only receive IPv6 so that they can share the port with an
+/// IPv4 socket
+fn bind_udp(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Receive magic packets on `address`, returns the address actually bound to
+async fn listen_udp(
+    address: SocketAddr,
+    captures: mpsc::UnboundedSender<Capture>,
+) -> Result<SocketAddr> {
+    let socket = bind_udp(address)?;
+    let local_address = socket.local_addr()?;
+
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        while let Ok((size, source)) = socket.recv_from(&mut buffer).await {
+            if let Some(packet) = decode_magic_packet(&buffer[..size]) {
+                let capture = Capture {
+                    source: source.to_string(),
+                    vlans: vec![],
+                    via: "udp",
+                    packet,
+                };
+                if captures.send(capture).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(local_address)
+}
+
+/// Print every magic packet seen on `interface` and/or the UDP ports 7 and 9, until interrupted
+pub async fn listen(servers: Vec<Server>, interface: Option<&str>, udp: bool) -> Result<()> {
+    if interface.is_none() && !udp {
+        return Err(ListenError::NothingToListenOn);
+    }
+
+    let (sender, mut captures) = mpsc::unbounded_channel();
+
+    if let Some(interface) = interface {
+        listen_ethernet(interface, sender.clone())?;
+        println!("Listening for magic packets on {}", interface.bold());
+    }
+    if udp {
+        let mut ipv6_error = None;
+        for port in WOL_UDP_PORTS {
+            listen_udp((Ipv4Addr::UNSPECIFIED, port).into(), sender.clone()).await?;
+            // Hosts without IPv6 can still receive the IPv4 packets
+            if let Err(e) = listen_udp((Ipv6Addr::UNSPECIFIED, port).into(), sender.clone()).await {
+                ipv6_error = Some(e);
+            }
+        }
+        match ipv6_error {
+            None => println!("Listening for magic packets on UDP ports 7 and 9"),
+            Some(e) => println!(
+                "Listening for magic packets on UDP ports 7 and 9, over IPv4 only ({})",
+                e
+            ),
+        }
+    }
+    drop(sender);
+
+    while let Some(capture) = captures.recv().await {
+        report(&capture, &servers);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::test_interface;
+    use crate::wol::{build_wol_packet, Destination, SecureOnPassword, Vlan, VlanTag};
+
+    #[test]
+    fn test_decode_ethernet_frame() {
+        let mac = "01:23:45:67:89:AB".parse::<MacAddr>().unwrap();
+        let frame =
+            build_wol_packet(mac, Destination::Broadcast, &test_interface(), None, None).unwrap();
+
+        let capture = decode_frame(&frame).expect("magic packet not found");
+        assert_eq!(
+            capture,
+            Capture {
+                source: "02:00:00:00:00:01".into(),
+                vlans: vec![],
+                via: "ethernet",
+                packet: MagicPacket {
+                    target: mac,
+                    password: None
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_qinq_frame_with_password() {
+        let mac = "01:23:45:67:89:AB".parse::<MacAddr>().unwrap();
+        let password = "a1:b2:c3:d4".parse::<SecureOnPassword>().unwrap();
+        let vlan = Vlan {
+            tag: VlanTag {
+                id: 100,
+                priority: 5,
+                dei: false,
+            },
+            outer: Some(VlanTag {
+                id: 20,
+                ..Default::default()
+            }),
+        };
+        let frame = build_wol_packet(
+            mac,
+            Destination::Broadcast,
+            &test_interface(),
+            Some(&vlan),
+            Some(&password),
+        )
+        .unwrap();
+
+        let capture = decode_frame(&frame).expect("magic packet not found");
+        assert_eq!(vec![20, 100], capture.vlans);
+        assert_eq!(mac, capture.packet.target);
+        assert_eq!(Some(password.bytes().to_vec()), capture.packet.password);
+    }
+
+    #[test]
+    fn test_decode_udp_frame() {
+        let mac = MacAddr::new(0x01, 0x23, 0x45, 0x67, 0x89, 0xAB);
+        let mut magic = vec![0xFF; 6];
+        magic.extend(mac.octets().repeat(16));
+
+        // Ethernet + IPv4 + UDP headers in front of the magic packet
+        let mut frame = vec![0xFF; 6];
+        frame.extend([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        frame.extend([0x08, 0x00]);
+        let total_length = (20 + 8 + magic.len()) as u16;
+        frame.extend([0x45, 0x00]);
+        frame.extend(total_length.to_be_bytes());
+        frame.extend([0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00]);
+        frame.extend([192, 168, 1, 2]);
+        frame.extend([192, 168, 1, 255]);
+        frame.extend(40000u16.to_be_bytes());
+        frame.extend(9u16.to_be_bytes());
+        frame.extend(((8 + magic.len()) as u16).to_be_bytes());
+        frame.extend([0x00, 0x00]);
+        frame.extend(&magic);
+
+        let capture = decode_frame(&frame).expect("magic packet not found");
+        assert_eq!("udp", capture.via);
+        assert_eq!("192.168.1.2:40000", capture.source);
+        assert_eq!(mac, capture.packet.target);
+    }
+
+    #[tokio::test]
+    async fn test_listen_udp_ipv6() {
+        let (sender, mut captures) = mpsc::unbounded_channel();
+
+        // Both sockets share the port
+        let address = listen_udp((Ipv4Addr::LOCALHOST, 0).into(), sender.clone())
+            .await
+            .expect("failed to listen over IPv4");
+        let address = listen_udp((Ipv6Addr::LOCALHOST, address.port()).into(), sender)
+            .await
+            .expect("failed to listen over IPv6");
+
+        let mac = MacAddr::new(0x01, 0x23, 0x45, 0x67, 0x89, 0xAB);
+        let mut magic = vec![0xFF; 6];
+        magic.extend(mac.octets().repeat(16));
+        let socket = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        socket.send_to(&magic, address).await.unwrap();
+
+        let capture = captures.recv().await.expect("magic packet not received");
+        assert_eq!("udp", capture.via);
+        assert_eq!(socket.local_addr().unwrap().to_string(), capture.source);
+        assert_eq!(mac, capture.packet.target);
+    }
+
+    #[test]
+    fn test_decode_not_a_magic_packet() {
+        // One of the repetitions is different
+        let mut payload = vec![0xFF; 6];
+        payload.extend([0x01, 0x23, 0x45, 0x67, 0x89, 0xAB].repeat(15));
+        payload.extend([0x01, 0x23, 0x45, 0x67, 0x89, 0xAC]);
+        assert_eq!(None, decode_magic_packet(&payload));
+
+        // Too short
+        assert_eq!(None, decode_magic_packet(&payload[..50]));
+    }
+}
@@ -1,4 +1,17 @@
+mod config;
+mod diagnostics;
+mod http_power;
+mod interfaces;
+mod interpolation;
+mod ipmi;
+mod listen;
+mod neighbours;
+mod orchestrator;
+mod pcap;
+mod redfish;
 mod servers;
+mod shell;
+mod transport;
 mod wol;
 
 use colored::*;
@@ -32,16 +45,33 @@ fn render_servers(servers: &Vec<servers::Server>, spinner_index: usize, backtrac
         // Display the server name and status
         let (icon, server_status) = match server.status {
             servers::ServerStatus::Waiting => ("◉".normal(), "waiting".normal()),
-            servers::ServerStatus::WOLSent => ("◉".yellow(), "WOL sent".yellow()),
+            servers::ServerStatus::WOLSent => (
+                "◉".yellow(),
+                format!("WOL sent ({})", server.packets_sent).yellow(),
+            ),
+            servers::ServerStatus::PowerOnSent => ("◉".yellow(), "power on sent".yellow()),
+            servers::ServerStatus::AlreadyUp => ("◉".green(), "already up".green()),
+            servers::ServerStatus::ShutdownSent => ("◉".yellow(), "shutdown sent".yellow()),
+            servers::ServerStatus::Down => ("◉".blue(), "down".blue()),
+            servers::ServerStatus::LeftRunning => ("◉".normal(), "left running".normal()),
             servers::ServerStatus::Ok => ("◉".green(), "ok".green()),
             servers::ServerStatus::TimedOut => ("◉".red(), "timed-out".red()),
         };
+        // Show the groups the server belongs to after its name
+        let tags = if server.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", server.tags.join(", "))
+                .dimmed()
+                .to_string()
+        };
         execute!(
             stdout,
             Print(format!(
-                "{} {}: {}\n",
+                "{} {}{}: {}\n",
                 icon,
                 server.name.bold(),
+                tags,
                 server_status
             ))
         )
@@ -131,23 +161,169 @@ async fn update_server_status(servers: Arc<RwLock<Vec<servers::Server>>>) {
     }
 }
 
+/// Check all servers at once before waking anything, so that servers left running after a
+/// partial outage are not woken up and waited on again
+async fn precheck_servers(servers: Arc<RwLock<Vec<servers::Server>>>) {
+    let server_count = servers.read().await.len();
+    let tasks: Vec<_> = (0..server_count)
+        .map(|index| tokio::spawn(servers::precheck_server(servers.clone(), index)))
+        .collect();
+    for task in tasks {
+        let _ = task.await;
+    }
+}
+
+/// Fill in the interface of every server that needs one to send its packets but did not configure one
+fn select_interfaces(
+    servers: &mut [servers::Server],
+    default_interface: Option<&str>,
+) -> Result<(), interfaces::InterfaceSelectionError> {
+    for server in servers.iter_mut() {
+        if server.interface.is_some() || !server.requires_interface() {
+            continue;
+        }
+        let selection =
+            interfaces::select_system_interface(&server.name, server.ip, default_interface)?;
+        println!("{}: using interface {}", server.name.bold(), selection);
+        server.interface = Some(selection.interface);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// Wake up the servers in dependency order
+    Up,
+    /// Print magic packets seen on the network
+    Listen,
+    /// Look up the MACs of servers that only have an `ip` and write them to the MAC cache
+    ResolveMacs,
+    /// Shut the servers down in reverse dependency order
+    Down,
+}
+
+struct Args {
+    command: Command,
+    config: String,
+    interface: Option<String>,
+    pcap: Option<String>,
+    pcap_only: bool,
+    udp: bool,
+    mac_cache: Option<String>,
+    format: Option<config::Format>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut command = None;
+    let mut config = None;
+    let mut interface = None;
+    let mut pcap = None;
+    let mut pcap_only = false;
+    let mut udp = false;
+    let mut mac_cache = None;
+    let mut format = None;
+
+    let mut args = args.skip(1).peekable();
+
+    // The subcommand, if any, has to come first
+    match args.peek().map(String::as_str) {
+        Some("listen") => command = Some(Command::Listen),
+        Some("resolve-macs") => command = Some(Command::ResolveMacs),
+        Some("down") => command = Some(Command::Down),
+        _ => {}
+    }
+    if command.is_some() {
+        args.next();
+    }
+    let command = command.unwrap_or(Command::Up);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-i" | "--interface" => interface = Some(args.next()?),
+            "--pcap" if command == Command::Up => pcap = Some(args.next()?),
+            "--pcap-only" if command == Command::Up => pcap_only = true,
+            "--udp" if command == Command::Listen => udp = true,
+            "--mac-cache" if matches!(command, Command::Up | Command::ResolveMacs) => {
+                mac_cache = Some(args.next()?)
+            }
+            "--format" => format = Some(args.next()?.parse().ok()?),
+            _ if arg.starts_with('-') => return None,
+            _ if config.is_none() => config = Some(arg),
+            _ => return None,
+        }
+    }
+
+    if pcap_only && pcap.is_none() {
+        return None;
+    }
+
+    Some(Args {
+        command,
+        config: config?,
+        interface,
+        pcap,
+        pcap_only,
+        udp,
+        mac_cache,
+        format,
+    })
+}
+
 fn print_help() {
-    println!("Usage: rallyup <file>");
+    println!("Usage: rallyup [options] <file>");
+    println!("       rallyup listen [listen options] <file>");
+    println!("       rallyup resolve-macs [resolve options] <file>");
+    println!("       rallyup down <file>");
     println!("rallyup: A tool to send Wake-on-LAN packets to servers in dependency order");
+    println!();
+    println!("Options:");
+    println!("  -i, --interface <name>  Default interface for servers without `interface` or a matching `ip`");
+    println!("  --pcap <file>           Record every WOL frame to a pcap file");
+    println!("  --pcap-only             With --pcap, record frames without sending them or running health checks");
+    println!("  --mac-cache <file>      MAC cache for servers without a `mac` (default: <file>.macs.yaml)");
+    println!("  --format <format>       Config format: yaml, toml or json (default: from the file extension)");
+    println!();
+    println!("Listen options:");
+    println!("  -i, --interface <name>  Capture magic packets on this interface");
+    println!("  --udp                   Also receive magic packets on UDP ports 7 and 9, over IPv4 and IPv6");
+    println!();
+    println!("Resolve options:");
+    println!("  -i, --interface <name>  Default interface to send ARP requests from");
+    println!("  --mac-cache <file>      Where to write the MACs (default: <file>.macs.yaml)");
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() != 2 {
+    let Some(args) = parse_args(env::args()) else {
         print_help();
         return Ok(());
+    };
+
+    let mut wake_order = servers::parse_server_dependencies(&args.config, args.format)?;
+
+    if args.command == Command::Listen {
+        listen::listen(wake_order, args.interface.as_deref(), args.udp).await?;
+        return Ok(());
     }
 
-    let filename = &args[1];
+    let mac_cache = match &args.mac_cache {
+        Some(path) => path.into(),
+        None => neighbours::default_cache_path(&args.config),
+    };
 
-    let wake_order = servers::parse_server_dependencies(filename)?;
+    if args.command == Command::ResolveMacs {
+        neighbours::resolve_macs(&wake_order, args.interface.as_deref(), &mac_cache).await?;
+        return Ok(());
+    }
+
+    if args.command == Command::Down {
+        for server in wake_order.iter_mut() {
+            server.use_down_checks();
+        }
+    } else {
+        neighbours::fill_in_macs(&mut wake_order, &neighbours::MacCache::load(&mac_cache)?)?;
+        select_interfaces(&mut wake_order, args.interface.as_deref())?;
+    }
 
     let mut line_count = 0;
     for server in wake_order.iter() {
@@ -161,32 +337,40 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // Need to keep it in a Arc<RwLock> since the status render loop will be reading
     // the server status while the health checks may be updating it concurrently
-    let servers = Arc::new(RwLock::new(wake_order.clone()));
+    let servers = Arc::new(RwLock::new(wake_order));
 
     tokio::spawn(update_server_status(servers.clone()));
 
-    for (server_index, server) in wake_order.into_iter().enumerate() {
-        wol::send_wol_packet(&server.mac, &server.interface, server.vlan)?;
-        {
-            let mut servers = servers.write().await;
-            servers[server_index].status = servers::ServerStatus::WOLSent;
+    let result = if args.command == Command::Down {
+        orchestrator::shut_down_servers(servers.clone()).await
+    } else {
+        // Nothing is woken up with --pcap-only, so every frame should be recorded
+        if !args.pcap_only {
+            precheck_servers(servers.clone()).await;
         }
 
-        let server_status = servers::perform_health_checks(servers.clone(), server_index).await;
-
-        if let servers::ServerStatus::TimedOut = server_status {
-            let servers = servers.read().await;
-            render_servers(&servers, 0, line_count);
-            return Err(anyhow::anyhow!(
-                "health check for {} timed out",
-                server.name
-            ));
+        // Keep the transport around for the whole run so that datalink channels are reused between servers
+        let mut transport = transport::SystemTransport::default();
+        if let Some(pcap) = &args.pcap {
+            transport.ethernet =
+                wol::WolSender::new().with_pcap(pcap::PcapWriter::create(pcap)?, args.pcap_only);
         }
-    }
+
+        orchestrator::wake_servers(servers.clone(), &mut transport).await
+    };
 
     {
         let servers = servers.read().await;
         render_servers(&servers, 0, line_count);
     }
+    if let Err(e) = result {
+        // Wake and shutdown actions can fail with the passwords and tokens they were given
+        let servers = servers.read().await;
+        let message = servers
+            .iter()
+            .fold(e.to_string(), |message, server| server.redact(&message));
+        return Err(anyhow::anyhow!(message));
+    }
+
     return Ok(());
 }
@@ -0,0 +1,474 @@
+use colored::Colorize;
+use pnet::datalink::{self, Channel::Ethernet, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::interfaces;
+use crate::servers::{Server, WakeMethod};
+use crate::wol::MacAddress;
+
+const PROC_NET_ARP: &str = "/proc/net/arp";
+// ATF_COM, the entry has a valid hardware address
+const ARP_FLAG_COMPLETE: u32 = 0x02;
+
+const SIZE_ARP_PACKET: usize = 28;
+const SIZE_ETHERNET_HEADER: usize = 14;
+
+// Hosts that are up answer within milliseconds, but the first request can be lost
+const ARP_TIMEOUT: Duration = Duration::from_secs(1);
+const ARP_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Error)]
+pub enum NeighbourError {
+    #[error("Failed to read MAC cache {0}: {1}")]
+    CacheReadError(String, String),
+
+    #[error("Failed to write MAC cache {0}: {1}")]
+    CacheWriteError(String, String),
+
+    #[error("No MAC address for {}", .0.join(", "))]
+    Unresolved(Vec<String>),
+
+    #[error("Failed to find network interface: {0}")]
+    InterfaceNotFound(String),
+
+    #[error("Network error: {0}")]
+    NetworkError(#[from] io::Error),
+
+    #[error(transparent)]
+    InterfaceSelectionError(#[from] interfaces::InterfaceSelectionError),
+}
+
+type Result<T> = std::result::Result<T, NeighbourError>;
+
+/// MAC addresses discovered by `rallyup resolve-macs`, keyed by IP address
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MacCache(BTreeMap<IpAddr, MacAddress>);
+
+impl MacCache {
+    /// Read the cache, a missing file is an empty cache
+    pub fn load(path: &Path) -> Result<MacCache> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(MacCache::default()),
+            Err(e) => {
+                return Err(NeighbourError::CacheReadError(
+                    path.display().to_string(),
+                    e.to_string(),
+                ))
+            }
+        };
+        serde_yaml_ng::from_str(&content)
+            .map_err(|e| NeighbourError::CacheReadError(path.display().to_string(), e.to_string()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let write_error =
+            |e: String| NeighbourError::CacheWriteError(path.display().to_string(), e);
+        let content = serde_yaml_ng::to_string(self).map_err(|e| write_error(e.to_string()))?;
+        fs::write(path, content).map_err(|e| write_error(e.to_string()))
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Option<MacAddress> {
+        self.0.get(&ip).copied()
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, mac: MacAddress) {
+        self.0.insert(ip, mac);
+    }
+}
+
+/// The cache lives next to the config, e.g. `servers.macs.yaml` for `servers.yaml`
+pub fn default_cache_path(config: &str) -> PathBuf {
+    Path::new(config).with_extension("macs.yaml")
+}
+
+/// Fill in the MAC of every server woken up with WOL that only configured an `ip`, every server
+/// that is not in the cache is reported at once
+pub fn fill_in_macs(servers: &mut [Server], cache: &MacCache) -> Result<()> {
+    let mut unresolved = Vec::new();
+    for server in servers.iter_mut() {
+        if server.mac.is_some() || !matches!(server.wake, WakeMethod::Wol) {
+            continue;
+        }
+        server.mac = server.ip.and_then(|ip| cache.get(ip));
+        let reason = match server.ip {
+            _ if server.mac.is_some() => continue,
+            None => "set `mac`",
+            // `resolve-macs` only speaks ARP, so these can never be in the cache
+            Some(IpAddr::V6(_)) => "IPv6 neighbours are not resolved, set `mac`",
+            Some(IpAddr::V4(_)) => "set `mac` or run `rallyup resolve-macs` while it is up",
+        };
+        unresolved.push(format!("{} ({})", server.name, reason));
+    }
+
+    if !unresolved.is_empty() {
+        return Err(NeighbourError::Unresolved(unresolved));
+    }
+    Ok(())
+}
+
+// IP address       HW type     Flags       HW address            Mask     Device
+// 192.168.1.1      0x1         0x2         00:11:22:33:44:55     *        eth0
+fn parse_arp_table(content: &str) -> HashMap<Ipv4Addr, MacAddress> {
+    content
+        .lines()
+        // Skip the header
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 {
+                return None;
+            }
+            let flags = u32::from_str_radix(fields[2].trim_start_matches("0x"), 16).ok()?;
+            if flags & ARP_FLAG_COMPLETE == 0 {
+                return None;
+            }
+            Some((fields[0].parse().ok()?, fields[3].parse().ok()?))
+        })
+        .collect()
+}
+
+/// Read the IPv4 neighbour table, empty on systems without `/proc/net/arp`
+pub fn system_neighbours() -> HashMap<Ipv4Addr, MacAddress> {
+    fs::read_to_string(PROC_NET_ARP)
+        .map(|content| parse_arp_table(&content))
+        .unwrap_or_default()
+}
+
+/// Broadcast "who has `target`? tell `source_ip`" frame
+fn build_arp_request(source_mac: MacAddr, source_ip: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+    let mut arp_buffer = [0u8; SIZE_ARP_PACKET];
+    let mut arp =
+        MutableArpPacket::new(&mut arp_buffer).expect("buffer is large enough for an ARP packet");
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Request);
+    arp.set_sender_hw_addr(source_mac);
+    arp.set_sender_proto_addr(source_ip);
+    arp.set_target_hw_addr(MacAddr::zero());
+    arp.set_target_proto_addr(target);
+
+    let mut buffer = vec![0u8; SIZE_ETHERNET_HEADER + SIZE_ARP_PACKET];
+    let mut frame =
+        MutableEthernetPacket::new(&mut buffer).expect("buffer is large enough for an ARP frame");
+    frame.set_destination(MacAddr::broadcast());
+    frame.set_source(source_mac);
+    frame.set_ethertype(EtherTypes::Arp);
+    frame.set_payload(&arp_buffer);
+
+    buffer
+}
+
+/// Sender of `frame` if it is an ARP reply from `target`
+fn decode_arp_reply(frame: &[u8], target: Ipv4Addr) -> Option<MacAddr> {
+    let frame = EthernetPacket::new(frame)?;
+    if frame.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+    let arp = ArpPacket::new(frame.payload())?;
+    if arp.get_operation() != ArpOperations::Reply || arp.get_sender_proto_addr() != target {
+        return None;
+    }
+    Some(arp.get_sender_hw_addr())
+}
+
+/// A datalink channel on an interface to send ARP requests and receive the replies on.
+///
+/// Opening a channel is relatively expensive, so one is opened per interface and reused for
+/// every server resolved through it.
+struct ArpChannel {
+    source_mac: MacAddr,
+    source_ip: Ipv4Addr,
+    tx: Box<dyn DataLinkSender>,
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+impl ArpChannel {
+    fn open(interface: &NetworkInterface) -> Result<ArpChannel> {
+        let source_mac = interface.mac.ok_or_else(|| {
+            NeighbourError::InterfaceNotFound(format!("{} has no MAC address", interface.name))
+        })?;
+        let source_ip = interface
+            .ips
+            .iter()
+            .find_map(|network| match network.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .ok_or_else(|| {
+                NeighbourError::InterfaceNotFound(format!("{} has no IPv4 address", interface.name))
+            })?;
+
+        let config = datalink::Config {
+            read_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        match datalink::channel(interface, config)? {
+            Ethernet(tx, rx) => Ok(ArpChannel {
+                source_mac,
+                source_ip,
+                tx,
+                rx,
+            }),
+            _ => Err(NeighbourError::NetworkError(io::Error::other(
+                "unhandled channel type for this interface",
+            ))),
+        }
+    }
+
+    /// Ask for the MAC of `target` with ARP requests, blocks until it answers or the attempts
+    /// run out
+    fn request(&mut self, target: Ipv4Addr) -> Result<Option<MacAddr>> {
+        let request = build_arp_request(self.source_mac, self.source_ip, target);
+        for _ in 0..ARP_ATTEMPTS {
+            self.tx
+                .send_to(&request, None)
+                .ok_or_else(|| io::Error::other("failed to send ARP request"))??;
+
+            let deadline = Instant::now() + ARP_TIMEOUT;
+            while Instant::now() < deadline {
+                match self.rx.next() {
+                    // Replies to earlier requests on the same channel are skipped here
+                    Ok(frame) => {
+                        if let Some(mac) = decode_arp_reply(frame, target) {
+                            return Ok(Some(mac));
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Look up the MAC of a single server, first in the neighbour table and then with ARP requests
+/// on the channel of its interface, which is opened on first use
+async fn resolve_mac(
+    server: &Server,
+    ip: Ipv4Addr,
+    neighbours: &HashMap<Ipv4Addr, MacAddress>,
+    default_interface: Option<&str>,
+    channels: &mut HashMap<String, ArpChannel>,
+) -> Result<Option<(MacAddress, String)>> {
+    if let Some(mac) = neighbours.get(&ip) {
+        return Ok(Some((*mac, "neighbour table".to_string())));
+    }
+
+    let interface_name = match &server.interface {
+        Some(interface) => interface.clone(),
+        None => {
+            interfaces::select_system_interface(
+                &server.name,
+                Some(IpAddr::V4(ip)),
+                default_interface,
+            )?
+            .interface
+        }
+    };
+    let mut channel = match channels.remove(&interface_name) {
+        Some(channel) => channel,
+        None => {
+            let interface = interfaces::find_interface(&interface_name)
+                .ok_or_else(|| NeighbourError::InterfaceNotFound(interface_name.clone()))?;
+            ArpChannel::open(&interface)?
+        }
+    };
+
+    // The datalink receiver blocks, so keep it off the async runtime
+    let (channel, mac) = tokio::task::spawn_blocking(move || {
+        let mac = channel.request(ip);
+        (channel, mac)
+    })
+    .await
+    .map_err(io::Error::other)?;
+    // A channel that failed may have gone bad, so it is reopened for the next server
+    let mac = mac?;
+    channels.insert(interface_name.clone(), channel);
+
+    // The reply can come from anything claiming the IP, so it gets the same checks as the config
+    Ok(mac
+        .and_then(|mac| mac.to_string().parse::<MacAddress>().ok())
+        .map(|mac| (mac, format!("ARP on {}", interface_name))))
+}
+
+/// Look up the MAC of every server woken up with WOL that only configured an `ip`, and add
+/// them to the cache at `cache_path`.
+///
+/// Has to run while the servers are up, the results are used to wake them on the next cold boot.
+pub async fn resolve_macs(
+    servers: &[Server],
+    default_interface: Option<&str>,
+    cache_path: &Path,
+) -> Result<()> {
+    let mut cache = MacCache::load(cache_path)?;
+    let neighbours = system_neighbours();
+    let mut channels = HashMap::new();
+
+    for server in servers {
+        if server.mac.is_some() || !matches!(server.wake, WakeMethod::Wol) {
+            continue;
+        }
+        let name = server.name.bold();
+
+        let ip = match server.ip {
+            Some(IpAddr::V4(ip)) => ip,
+            Some(IpAddr::V6(ip)) => {
+                println!("{}: {} ({} is not IPv4)", name, "skipped".yellow(), ip);
+                continue;
+            }
+            None => continue,
+        };
+
+        match resolve_mac(server, ip, &neighbours, default_interface, &mut channels).await {
+            Ok(Some((mac, source))) => {
+                println!("{}: {} ({})", name, mac.to_string().green(), source);
+                cache.insert(IpAddr::V4(ip), mac);
+            }
+            Ok(None) => println!("{}: {} ({} did not answer)", name, "not found".red(), ip),
+            Err(e) => println!("{}: {} ({})", name, "failed".red(), e),
+        }
+    }
+
+    cache.save(cache_path)?;
+    println!("MAC addresses written to {}", cache_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::MutablePacket;
+
+    const ARP_TABLE: &str =
+        "IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         00:11:22:33:44:55     *        eth0
+192.168.1.20     0x1         0x0         00:00:00:00:00:00     *        eth0
+10.0.0.5         0x1         0x6         00:1a:2b:3c:4d:5e     *        eth1
+";
+
+    #[test]
+    fn test_parse_arp_table() {
+        let neighbours = parse_arp_table(ARP_TABLE);
+        assert_eq!(2, neighbours.len());
+        assert_eq!(
+            "00:11:22:33:44:55",
+            neighbours[&Ipv4Addr::new(192, 168, 1, 1)].to_string()
+        );
+        assert_eq!(
+            "00:1a:2b:3c:4d:5e",
+            neighbours[&Ipv4Addr::new(10, 0, 0, 5)].to_string()
+        );
+        // Incomplete entries have no hardware address yet
+        assert!(!neighbours.contains_key(&Ipv4Addr::new(192, 168, 1, 20)));
+    }
+
+    #[test]
+    fn test_arp_request_and_reply() {
+        let source_mac = MacAddr::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+        let source_ip = Ipv4Addr::new(192, 168, 1, 2);
+        let target = Ipv4Addr::new(192, 168, 1, 10);
+
+        let request = build_arp_request(source_mac, source_ip, target);
+        assert_eq!(42, request.len());
+        assert_eq!([0xFF; 6], request[..6]);
+        assert_eq!(source_mac.octets(), request[6..12]);
+        assert_eq!([0x08, 0x06], request[12..14]);
+        // Ethernet, IPv4, 6 byte MACs, 4 byte IPs, request
+        assert_eq!(
+            [0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01],
+            request[14..22]
+        );
+        assert_eq!(target.octets(), request[38..42]);
+
+        // A request is not a reply
+        assert_eq!(None, decode_arp_reply(&request, target));
+
+        let target_mac = MacAddr::new(0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E);
+        let mut reply = request.clone();
+        {
+            let mut frame = MutableEthernetPacket::new(&mut reply).unwrap();
+            frame.set_source(target_mac);
+            let mut arp = MutableArpPacket::new(frame.payload_mut()).unwrap();
+            arp.set_operation(ArpOperations::Reply);
+            arp.set_sender_hw_addr(target_mac);
+            arp.set_sender_proto_addr(target);
+            arp.set_target_hw_addr(source_mac);
+            arp.set_target_proto_addr(source_ip);
+        }
+        assert_eq!(Some(target_mac), decode_arp_reply(&reply, target));
+        assert_eq!(None, decode_arp_reply(&reply, source_ip));
+    }
+
+    #[test]
+    fn test_mac_cache() {
+        let yaml_data = r#"
+        - name: "server1"
+          ip: 192.168.1.10
+
+        - name: "server2"
+          mac: "00:11:22:33:44:55"
+          ip: 192.168.1.11
+
+        - name: "server3"
+          ip: 192.168.1.12
+
+        - name: "server4"
+          ip: fd00::14
+        "#;
+        let mut servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        let path =
+            std::env::temp_dir().join(format!("rallyup-test-{}.macs.yaml", std::process::id()));
+        assert_eq!(MacCache::default(), MacCache::load(&path).unwrap());
+
+        let mut cache = MacCache::default();
+        cache.insert(
+            "192.168.1.10".parse().unwrap(),
+            "00-1A-2B-3C-4D-5E".parse().unwrap(),
+        );
+        cache.save(&path).unwrap();
+        let cache = MacCache::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Every server missing from the cache is reported, with what to do about it
+        match fill_in_macs(&mut servers, &cache) {
+            Err(NeighbourError::Unresolved(unresolved)) => assert_eq!(
+                vec![
+                    "server3 (set `mac` or run `rallyup resolve-macs` while it is up)",
+                    "server4 (IPv6 neighbours are not resolved, set `mac`)",
+                ],
+                unresolved
+            ),
+            result => panic!("Expected unresolved servers, got {:?}", result),
+        }
+        assert_eq!("00:1a:2b:3c:4d:5e", servers[0].mac.unwrap().to_string());
+        // Configured MACs take precedence over the cache
+        assert_eq!("00:11:22:33:44:55", servers[1].mac.unwrap().to_string());
+    }
+
+    #[test]
+    fn test_default_cache_path() {
+        assert_eq!(
+            PathBuf::from("/etc/rallyup/servers.macs.yaml"),
+            default_cache_path("/etc/rallyup/servers.yaml")
+        );
+    }
+}
@@ -0,0 +1,574 @@
+use std::sync::Arc;
+use tokio::{sync::RwLock, time::sleep};
+
+use thiserror::Error;
+
+use crate::http_power::{self, HttpPowerError};
+use crate::ipmi::{self, IpmiError};
+use crate::redfish::{self, RedfishError};
+use crate::servers::{self, Server, ServerStatus, ShutdownMethod, WakeMethod};
+use crate::shell::{self, ShellError};
+use crate::transport::WakeTransport;
+use crate::wol::WOLError;
+
+#[derive(Debug, Error)]
+pub enum WakeError {
+    #[error(transparent)]
+    WOLError(#[from] WOLError),
+
+    #[error("failed to power on {0}: {1}")]
+    RedfishError(String, RedfishError),
+
+    #[error("failed to power on {0}: {1}")]
+    IpmiError(String, IpmiError),
+
+    #[error("failed to power on {0}: {1}")]
+    HttpPowerError(String, HttpPowerError),
+
+    #[error("failed to power on {0}: {1}")]
+    ShellError(String, ShellError),
+
+    #[error("health check for {0} timed out")]
+    TimedOut(String),
+
+    #[error("failed to shut down {0}: {1}")]
+    ShutdownError(String, String),
+
+    #[error("{0} did not shut down in time")]
+    ShutdownTimedOut(String),
+}
+
+/// Send `wol_repeat.count` magic packets, `wol_repeat.interval` apart
+async fn send_wol_burst(
+    transport: &mut dyn WakeTransport,
+    servers: &Arc<RwLock<Vec<Server>>>,
+    server_index: usize,
+) -> Result<(), WOLError> {
+    let server = servers.read().await[server_index].clone();
+    let target = server.wake_target();
+
+    for i in 0..server.wol_repeat.count {
+        if i > 0 {
+            sleep(server.wol_repeat.interval).await;
+        }
+        transport.send(&target)?;
+        {
+            let mut servers = servers.write().await;
+            servers[server_index].status = ServerStatus::WOLSent;
+            servers[server_index].packets_sent += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Power on a server with any of the methods other than WOL
+async fn power_on(
+    servers: &Arc<RwLock<Vec<Server>>>,
+    server_index: usize,
+) -> Result<(), WakeError> {
+    let server = servers.read().await[server_index].clone();
+
+    match &server.wake {
+        WakeMethod::Wol => unreachable!("WOL is sent through the transport"),
+        WakeMethod::Redfish(config) => redfish::power_on(config)
+            .await
+            .map_err(|e| WakeError::RedfishError(server.name.clone(), e))?,
+        WakeMethod::Ipmi(config) => ipmi::power_on(config)
+            .await
+            .map_err(|e| WakeError::IpmiError(server.name.clone(), e))?,
+        WakeMethod::HttpPower(config) => http_power::power_on(config)
+            .await
+            .map_err(|e| WakeError::HttpPowerError(server.name.clone(), e))?,
+        WakeMethod::Shell(config) => shell::run_action(config, &server.environment())
+            .await
+            .map_err(|e| WakeError::ShellError(server.name.clone(), e))?,
+    }
+
+    servers.write().await[server_index].status = ServerStatus::PowerOnSent;
+    Ok(())
+}
+
+/// Wake up the servers one by one, in the order they are given, waiting for the health checks
+/// of each server to pass before moving on to the next one.
+///
+/// Servers marked as [`ServerStatus::AlreadyUp`] are skipped.
+/// Stops at the first server that fails to come up.
+pub async fn wake_servers(
+    servers: Arc<RwLock<Vec<Server>>>,
+    transport: &mut dyn WakeTransport,
+) -> Result<(), WakeError> {
+    let server_count = servers.read().await.len();
+
+    for server_index in 0..server_count {
+        let server = servers.read().await[server_index].clone();
+        if server.status == ServerStatus::AlreadyUp {
+            continue;
+        }
+        let is_wol = matches!(server.wake, WakeMethod::Wol);
+
+        if is_wol {
+            send_wol_burst(transport, &servers, server_index).await?;
+        } else if !transport.is_dry_run() {
+            power_on(&servers, server_index).await?;
+        }
+
+        // Nothing was sent, so there is no point waiting for the server to come up
+        if transport.is_dry_run() {
+            continue;
+        }
+
+        let health_checks = servers::perform_health_checks(servers.clone(), server_index);
+        tokio::pin!(health_checks);
+
+        // Packets are sometimes dropped while switches are still powering up,
+        // so keep re-sending them for as long as the checks are failing
+        let server_status = match server.rewake_every {
+            Some(rewake_every) if is_wol => loop {
+                tokio::select! {
+                    status = &mut health_checks => break status,
+                    _ = sleep(rewake_every) => {
+                        send_wol_burst(transport, &servers, server_index).await?;
+                    }
+                }
+            },
+            _ => health_checks.await,
+        };
+
+        if let ServerStatus::TimedOut = server_status {
+            return Err(WakeError::TimedOut(server.name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the `shutdown` action of a server, returns false if it does not have one
+async fn shut_down(
+    servers: &Arc<RwLock<Vec<Server>>>,
+    server_index: usize,
+) -> Result<bool, WakeError> {
+    let server = servers.read().await[server_index].clone();
+
+    let result = match &server.shutdown {
+        None => return Ok(false),
+        Some(ShutdownMethod::Shell(config)) => shell::run_action(config, &server.environment())
+            .await
+            .map_err(|e| e.to_string()),
+        Some(ShutdownMethod::Ssh(config)) => {
+            shell::ssh_shutdown(config).await.map_err(|e| e.to_string())
+        }
+        Some(ShutdownMethod::Redfish(config)) => {
+            redfish::power_off(config).await.map_err(|e| e.to_string())
+        }
+        Some(ShutdownMethod::Ipmi(config)) => {
+            ipmi::power_off(config).await.map_err(|e| e.to_string())
+        }
+    };
+    result.map_err(|e| WakeError::ShutdownError(server.name.clone(), e))?;
+
+    servers.write().await[server_index].status = ServerStatus::ShutdownSent;
+    Ok(true)
+}
+
+/// Shut the servers down in the reverse of the order they are given, waiting for the "is down"
+/// checks of each server to pass before moving on to its dependencies.
+///
+/// Expects the checks of every server to have been replaced with [`Server::use_down_checks`].
+/// Servers whose checks already pass are not shut down again. Servers without a `shutdown` action
+/// are left running, and so is everything they depend on, directly or not.
+/// Stops at the first server that fails to go down.
+pub async fn shut_down_servers(servers: Arc<RwLock<Vec<Server>>>) -> Result<(), WakeError> {
+    let server_count = servers.read().await.len();
+    let mut left_running: Vec<Server> = Vec::new();
+
+    for server_index in (0..server_count).rev() {
+        if servers::precheck_server_down(servers.clone(), server_index).await {
+            continue;
+        }
+        // Servers are shut down after everything depending on them, so every server left running
+        // that needs this one is already known
+        let server = servers.read().await[server_index].clone();
+        let needed = left_running
+            .iter()
+            .any(|running| running.depends_on(&server));
+        if needed || !shut_down(&servers, server_index).await? {
+            servers.write().await[server_index].status = ServerStatus::LeftRunning;
+            left_running.push(server);
+            continue;
+        }
+
+        if let ServerStatus::TimedOut =
+            servers::perform_health_checks(servers.clone(), server_index).await
+        {
+            let name = servers.read().await[server_index].name.clone();
+            return Err(WakeError::ShutdownTimedOut(name));
+        }
+        servers.write().await[server_index].status = ServerStatus::Down;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::RecordingTransport;
+
+    fn servers_from_yaml(yaml_data: &str) -> Arc<RwLock<Vec<Server>>> {
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        Arc::new(RwLock::new(servers))
+    }
+
+    #[tokio::test]
+    async fn test_wake_sequence() {
+        let servers = servers_from_yaml(
+            r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          transport: udp
+          wol_repeat:
+            count: 3
+            interval: 10ms
+          depends:
+            - "server1"
+          check:
+            - type: shell
+              command: "echo ready"
+              regex: "ready"
+        "#,
+        );
+
+        let mut transport = RecordingTransport::default();
+        wake_servers(servers.clone(), &mut transport)
+            .await
+            .expect("wake sequence should succeed");
+
+        let sent: Vec<&str> = transport.sent.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(vec!["server1", "server2", "server2", "server2"], sent);
+        assert_eq!(Some("eth0".to_string()), transport.sent[0].interface);
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::Ok, servers[0].status);
+        assert_eq!(1, servers[0].packets_sent);
+        assert_eq!(ServerStatus::Ok, servers[1].status);
+        assert_eq!(3, servers[1].packets_sent);
+    }
+
+    #[tokio::test]
+    async fn test_wake_sequence_with_redfish() {
+        let mut bmc = mockito::Server::new_async().await;
+        let mock = bmc
+            .mock("POST", "/redfish/v1/Systems/1/Actions/ComputerSystem.Reset")
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let servers = servers_from_yaml(
+            &r#"
+        - name: "bmc-server"
+          wake:
+            type: redfish
+            url: <url>
+            system: /redfish/v1/Systems/1
+            username: admin
+            password: secret
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+
+        - name: "wol-server"
+          mac: "66:77:88:99:AA:BB"
+          interface: "eth0"
+          depends:
+            - "bmc-server"
+        "#
+            .replace("<url>", &bmc.url()),
+        );
+
+        let mut transport = RecordingTransport::default();
+        wake_servers(servers.clone(), &mut transport)
+            .await
+            .expect("wake sequence should succeed");
+
+        mock.assert_async().await;
+        let sent: Vec<&str> = transport.sent.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(vec!["wol-server"], sent);
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::Ok, servers[0].status);
+        assert_eq!(0, servers[0].packets_sent);
+        assert_eq!(ServerStatus::Ok, servers[1].status);
+    }
+
+    #[tokio::test]
+    async fn test_wake_sequence_with_shell() {
+        let servers = servers_from_yaml(
+            r#"
+        - name: "script-server"
+          mac: "00:11:22:33:44:55"
+          wake:
+            type: shell
+            command: test "$RALLYUP_NAME:$RALLYUP_MAC" = "script-server:00:11:22:33:44:55"
+
+        - name: "broken-script"
+          wake:
+            type: shell
+            command: exit 2
+          depends:
+            - "script-server"
+        "#,
+        );
+
+        let mut transport = RecordingTransport::default();
+        let result = wake_servers(servers.clone(), &mut transport).await;
+
+        assert!(
+            matches!(result, Err(WakeError::ShellError(name, ShellError::UnexpectedStatus(_, _))) if name == "broken-script")
+        );
+        assert!(transport.sent.is_empty());
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::Ok, servers[0].status);
+        assert_eq!(ServerStatus::Waiting, servers[1].status);
+    }
+
+    #[tokio::test]
+    async fn test_wake_sequence_skips_servers_already_up() {
+        let servers = servers_from_yaml(
+            r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          interface: "eth0"
+          depends:
+            - "server1"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+        "#,
+        );
+        servers.write().await[0].status = ServerStatus::AlreadyUp;
+
+        let mut transport = RecordingTransport::default();
+        wake_servers(servers.clone(), &mut transport)
+            .await
+            .expect("wake sequence should succeed");
+
+        let sent: Vec<&str> = transport.sent.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(vec!["server2"], sent);
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::AlreadyUp, servers[0].status);
+        assert_eq!(0, servers[0].packets_sent);
+        assert_eq!(ServerStatus::Ok, servers[1].status);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sequence() {
+        let log = std::env::temp_dir().join(format!("rallyup-test-{}.log", std::process::id()));
+        let servers = servers_from_yaml(
+            &r#"
+        - name: "storage"
+          mac: "00:11:22:33:44:55"
+          shutdown:
+            type: shell
+            command: echo $RALLYUP_NAME >> <log>
+          down_check:
+            - type: shell
+              command: "! grep -qs storage <log>"
+              status: 0
+
+        - name: "firewall"
+          mac: "66:77:88:99:AA:BB"
+
+        - name: "hypervisor"
+          mac: "AA:BB:CC:DD:EE:00"
+          depends:
+            - "storage"
+            - "firewall"
+          shutdown:
+            type: shell
+            command: echo $RALLYUP_NAME >> <log>
+          down_check:
+            - type: shell
+              command: "true"
+              status: 0
+              retry: 50ms
+              timeout: 1s
+        "#
+            .replace("<log>", &log.display().to_string()),
+        );
+        for server in servers.write().await.iter_mut() {
+            server.use_down_checks();
+        }
+
+        // The hypervisor never goes down, so its dependencies are left alone
+        let result = shut_down_servers(servers.clone()).await;
+        assert!(matches!(result, Err(WakeError::ShutdownTimedOut(name)) if name == "hypervisor"));
+        assert_eq!(
+            "hypervisor\n",
+            std::fs::read_to_string(&log).expect("failed to read log")
+        );
+        assert_eq!(ServerStatus::Waiting, servers.read().await[0].status);
+
+        servers.write().await[2].check[0].method = servers::HealthCheckMethod::Shell {
+            command: format!("! grep -qs hypervisor {}", log.display()),
+            status: Some(0),
+            regex: None,
+        };
+        std::fs::remove_file(&log).unwrap();
+
+        shut_down_servers(servers.clone())
+            .await
+            .expect("shutdown sequence should succeed");
+        let order = std::fs::read_to_string(&log).expect("failed to read log");
+        assert_eq!("hypervisor\nstorage\n", order);
+
+        {
+            let servers = servers.read().await;
+            assert_eq!(ServerStatus::Down, servers[0].status);
+            assert_eq!(ServerStatus::LeftRunning, servers[1].status);
+            assert_eq!(ServerStatus::Down, servers[2].status);
+        }
+
+        // Running it again does not shut down the servers that are already down
+        for server in servers.write().await.iter_mut() {
+            server.status = ServerStatus::Waiting;
+        }
+        shut_down_servers(servers.clone())
+            .await
+            .expect("shutdown sequence should succeed");
+        let rerun = std::fs::read_to_string(&log).expect("failed to read log");
+        std::fs::remove_file(&log).unwrap();
+        assert_eq!(order, rerun);
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::Down, servers[0].status);
+        assert_eq!(ServerStatus::LeftRunning, servers[1].status);
+        assert_eq!(ServerStatus::Down, servers[2].status);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_keeps_dependencies_running() {
+        let log = std::env::temp_dir().join(format!(
+            "rallyup-test-dependencies-{}.log",
+            std::process::id()
+        ));
+        let servers = servers_from_yaml(
+            &r#"
+        - name: "network"
+          mac: "00:11:22:33:44:55"
+          shutdown:
+            type: shell
+            command: echo $RALLYUP_NAME >> <log>
+          down_check:
+            - type: shell
+              command: "! grep -qs network <log>"
+              status: 0
+
+        - name: "storage"
+          mac: "66:77:88:99:AA:BB"
+          tags: ["nas"]
+          depends:
+            - "network"
+          shutdown:
+            type: shell
+            command: echo $RALLYUP_NAME >> <log>
+          down_check:
+            - type: shell
+              command: "! grep -qs storage <log>"
+              status: 0
+
+        - name: "backup"
+          mac: "66:77:88:99:AA:CC"
+          shutdown:
+            type: shell
+            command: echo $RALLYUP_NAME >> <log>
+          down_check:
+            - type: shell
+              command: "! grep -qs backup <log>"
+              status: 0
+
+        - name: "hypervisor"
+          mac: "AA:BB:CC:DD:EE:00"
+          depends:
+            - "tag:nas"
+        "#
+            .replace("<log>", &log.display().to_string()),
+        );
+        for server in servers.write().await.iter_mut() {
+            server.use_down_checks();
+        }
+
+        // The hypervisor has no shutdown action, so the storage it runs on, and the network the
+        // storage needs, are left running as well
+        shut_down_servers(servers.clone())
+            .await
+            .expect("shutdown sequence should succeed");
+        let order = std::fs::read_to_string(&log).expect("failed to read log");
+        std::fs::remove_file(&log).unwrap();
+        assert_eq!("backup\n", order);
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::LeftRunning, servers[0].status);
+        assert_eq!(ServerStatus::LeftRunning, servers[1].status);
+        assert_eq!(ServerStatus::Down, servers[2].status);
+        assert_eq!(ServerStatus::LeftRunning, servers[3].status);
+    }
+
+    #[tokio::test]
+    async fn test_wake_sequence_stops_on_timeout() {
+        let servers = servers_from_yaml(
+            r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          rewake_every: 200ms
+          check:
+            - type: shell
+              command: "false"
+              status: 0
+              retry: 50ms
+              timeout: 1s
+
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          interface: "eth0"
+          depends:
+            - "server1"
+        "#,
+        );
+
+        let mut transport = RecordingTransport::default();
+        let result = wake_servers(servers.clone(), &mut transport).await;
+
+        assert!(matches!(result, Err(WakeError::TimedOut(name)) if name == "server1"));
+
+        // The initial packet, then re-sent while the check was failing
+        assert!(transport.sent.len() >= 3);
+        assert!(transport.sent.iter().all(|t| t.name == "server1"));
+
+        let servers = servers.read().await;
+        assert_eq!(ServerStatus::TimedOut, servers[0].status);
+        assert_eq!(transport.sent.len() as u32, servers[0].packets_sent);
+        assert_eq!(ServerStatus::Waiting, servers[1].status);
+    }
+}
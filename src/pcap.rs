@@ -0,0 +1,125 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Classic libpcap file format, see https://wiki.wireshark.org/Development/LibpcapFileFormat
+//
+// Global header (24 bytes):
+// | Magic (0xA1B2C3D4) | Major (2) | Minor (4) | Timezone offset | Timestamp accuracy | Snapshot length | Link type |
+// | 4 bytes            | 2 bytes   | 2 bytes   | 4 bytes         | 4 bytes            | 4 bytes         | 4 bytes   |
+//
+// Followed by one record per frame (16 byte header + frame):
+// | Seconds | Microseconds | Captured length | Original length | Frame |
+// | 4 bytes | 4 bytes      | 4 bytes         | 4 bytes         | ...   |
+//
+// All fields are written in native byte order, readers detect it from the magic number.
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+const SIZE_GLOBAL_HEADER: usize = 24;
+const SIZE_RECORD_HEADER: usize = 16;
+
+/// Writes Ethernet frames to a pcap file that can be opened in Wireshark or tcpdump
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl PcapWriter<File> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        PcapWriter::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(SIZE_GLOBAL_HEADER);
+        header.extend_from_slice(&PCAP_MAGIC.to_ne_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_ne_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_ne_bytes());
+        // Timestamps are in UTC
+        header.extend_from_slice(&0i32.to_ne_bytes());
+        header.extend_from_slice(&0u32.to_ne_bytes());
+        header.extend_from_slice(&PCAP_SNAPLEN.to_ne_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(PcapWriter { writer })
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8], timestamp: SystemTime) -> io::Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let length = frame.len() as u32;
+
+        // Written in a single call so that an interrupted run never leaves a partial record
+        let mut record = Vec::with_capacity(SIZE_RECORD_HEADER + frame.len());
+        record.extend_from_slice(&(since_epoch.as_secs() as u32).to_ne_bytes());
+        record.extend_from_slice(&since_epoch.subsec_micros().to_ne_bytes());
+        record.extend_from_slice(&length.min(PCAP_SNAPLEN).to_ne_bytes());
+        record.extend_from_slice(&length.to_ne_bytes());
+        record.extend_from_slice(&frame[..frame.len().min(PCAP_SNAPLEN as usize)]);
+
+        self.writer.write_all(&record)?;
+        self.writer.flush()
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pcap_header() {
+        let pcap = PcapWriter::new(Vec::new()).expect("failed to write pcap header");
+        let buffer = pcap.into_inner();
+
+        assert_eq!(SIZE_GLOBAL_HEADER, buffer.len());
+        assert_eq!(PCAP_MAGIC.to_ne_bytes(), buffer[..4]);
+        assert_eq!(2u16.to_ne_bytes(), buffer[4..6]);
+        assert_eq!(4u16.to_ne_bytes(), buffer[6..8]);
+        assert_eq!(65535u32.to_ne_bytes(), buffer[16..20]);
+        assert_eq!(1u32.to_ne_bytes(), buffer[20..24]);
+    }
+
+    #[test]
+    fn test_pcap_records() {
+        let mut pcap = PcapWriter::new(Vec::new()).expect("failed to write pcap header");
+
+        let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        pcap.write_frame(&[0xAA; 60], timestamp).unwrap();
+        pcap.write_frame(&[0xBB; 20], timestamp).unwrap();
+
+        let buffer = pcap.into_inner();
+        assert_eq!(
+            SIZE_GLOBAL_HEADER + 2 * SIZE_RECORD_HEADER + 60 + 20,
+            buffer.len()
+        );
+
+        let record = &buffer[SIZE_GLOBAL_HEADER..];
+        // Seconds
+        assert_eq!(1_700_000_000u32.to_ne_bytes(), record[..4]);
+        // Microseconds
+        assert_eq!(123_456u32.to_ne_bytes(), record[4..8]);
+        // Captured and original length
+        assert_eq!(60u32.to_ne_bytes(), record[8..12]);
+        assert_eq!(60u32.to_ne_bytes(), record[12..16]);
+        // Frame
+        assert_eq!(vec![0xAA; 60], record[16..76]);
+
+        let record = &record[SIZE_RECORD_HEADER + 60..];
+        assert_eq!(20u32.to_ne_bytes(), record[8..12]);
+        assert_eq!(vec![0xBB; 20], record[16..]);
+    }
+}
@@ -0,0 +1,301 @@
+use reqwest::{Certificate, Client, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{fs, time::Duration};
+
+use thiserror::Error;
+
+const SYSTEMS_PATH: &str = "/redfish/v1/Systems";
+const RESET_ACTION: &str = "Actions/ComputerSystem.Reset";
+
+const RESET_TYPE_ON: &str = "On";
+const RESET_TYPE_OFF: &str = "GracefulShutdown";
+
+fn default_request_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Error)]
+pub enum RedfishError {
+    #[error("Invalid Redfish URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("Failed to read CA certificate {0}: {1}")]
+    BadCertificate(String, String),
+
+    #[error("Redfish request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("No computer system found at {0}")]
+    NoSystem(String),
+
+    #[error("Redfish {0} failed with status {1}: {2}")]
+    ActionFailed(String, u16, String),
+}
+
+type Result<T> = std::result::Result<T, RedfishError>;
+
+/// Power a server on through its BMC with the Redfish `ComputerSystem.Reset` action
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedfishConfig {
+    /// Base URL of the BMC, e.g. `https://10.0.0.5`
+    pub url: String,
+    /// Path of the computer system, e.g. `/redfish/v1/Systems/1`.
+    /// The first system listed by the BMC is used when omitted.
+    #[serde(default)]
+    pub system: Option<String>,
+    pub username: String,
+    pub password: String,
+
+    /// Accept self-signed and otherwise invalid certificates, which most BMCs ship with
+    #[serde(default)]
+    pub insecure: bool,
+    /// PEM encoded CA certificate to validate the BMC certificate against
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+
+    /// `ResetType` to send, `On` when powering on and `GracefulShutdown` when shutting down
+    #[serde(default)]
+    pub reset_type: Option<String>,
+    #[serde(default = "default_request_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl RedfishConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.base_url()?;
+        Ok(())
+    }
+
+    fn base_url(&self) -> Result<Url> {
+        let url = Url::parse(&self.url).map_err(|e| RedfishError::InvalidUrl(e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(RedfishError::InvalidUrl(format!(
+                "{} is not an http(s) URL",
+                self.url
+            )));
+        }
+        Ok(url)
+    }
+
+    fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
+            .timeout(self.timeout)
+            .danger_accept_invalid_certs(self.insecure);
+
+        if let Some(ca_cert) = &self.ca_cert {
+            let pem = fs::read(ca_cert)
+                .map_err(|e| RedfishError::BadCertificate(ca_cert.clone(), e.to_string()))?;
+            let certificate = Certificate::from_pem(&pem)
+                .map_err(|e| RedfishError::BadCertificate(ca_cert.clone(), e.to_string()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url> {
+        self.base_url()?
+            .join(path)
+            .map_err(|e| RedfishError::InvalidUrl(e.to_string()))
+    }
+}
+
+/// Path of the first computer system in the `/redfish/v1/Systems` collection
+async fn discover_system(client: &Client, config: &RedfishConfig) -> Result<String> {
+    let url = config.endpoint(SYSTEMS_PATH)?;
+    let collection: Value = client
+        .get(url.clone())
+        .basic_auth(&config.username, Some(&config.password))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    collection["Members"]
+        .as_array()
+        .and_then(|members| members.first())
+        .and_then(|member| member["@odata.id"].as_str())
+        .map(|system| system.to_string())
+        .ok_or_else(|| RedfishError::NoSystem(url.to_string()))
+}
+
+/// Send `ComputerSystem.Reset` with the given reset type
+async fn reset(config: &RedfishConfig, reset_type: &str) -> Result<()> {
+    let client = config.client()?;
+
+    let system = match &config.system {
+        Some(system) => system.clone(),
+        None => discover_system(&client, config).await?,
+    };
+
+    let action = format!("{}/{}", system.trim_end_matches('/'), RESET_ACTION);
+    let response = client
+        .post(config.endpoint(&action)?)
+        .basic_auth(&config.username, Some(&config.password))
+        .json(&json!({ "ResetType": reset_type }))
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(RedfishError::ActionFailed(
+            format!("{} {}", RESET_ACTION, reset_type),
+            status.as_u16(),
+            body,
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn power_on(config: &RedfishConfig) -> Result<()> {
+    reset(
+        config,
+        config.reset_type.as_deref().unwrap_or(RESET_TYPE_ON),
+    )
+    .await
+}
+
+pub async fn power_off(config: &RedfishConfig) -> Result<()> {
+    reset(
+        config,
+        config.reset_type.as_deref().unwrap_or(RESET_TYPE_OFF),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    fn config(url: &str, system: Option<&str>) -> RedfishConfig {
+        RedfishConfig {
+            url: url.to_string(),
+            system: system.map(|s| s.to_string()),
+            username: "admin".into(),
+            password: "secret".into(),
+            insecure: false,
+            ca_cert: None,
+            reset_type: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    // admin:secret
+    const BASIC_AUTH: &str = "Basic YWRtaW46c2VjcmV0";
+
+    #[tokio::test]
+    async fn test_power_on() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/redfish/v1/Systems/1/Actions/ComputerSystem.Reset")
+            .match_header("authorization", BASIC_AUTH)
+            .match_body(Matcher::Json(json!({ "ResetType": "On" })))
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let result = power_on(&config(&server.url(), Some("/redfish/v1/Systems/1"))).await;
+        assert!(result.is_ok(), "{:?}", result);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_off() {
+        let mut server = mockito::Server::new_async().await;
+        let graceful = server
+            .mock("POST", "/redfish/v1/Systems/1/Actions/ComputerSystem.Reset")
+            .match_body(Matcher::Json(json!({ "ResetType": "GracefulShutdown" })))
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let mut config = config(&server.url(), Some("/redfish/v1/Systems/1"));
+        let result = power_off(&config).await;
+        assert!(result.is_ok(), "{:?}", result);
+        graceful.assert_async().await;
+
+        let force = server
+            .mock("POST", "/redfish/v1/Systems/1/Actions/ComputerSystem.Reset")
+            .match_body(Matcher::Json(json!({ "ResetType": "ForceOff" })))
+            .with_status(204)
+            .create_async()
+            .await;
+
+        config.reset_type = Some("ForceOff".into());
+        let result = power_off(&config).await;
+        assert!(result.is_ok(), "{:?}", result);
+        force.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_discovers_system() {
+        let mut server = mockito::Server::new_async().await;
+        let systems = server
+            .mock("GET", "/redfish/v1/Systems")
+            .match_header("authorization", BASIC_AUTH)
+            .with_status(200)
+            .with_body(r#"{"Members": [{"@odata.id": "/redfish/v1/Systems/System.Embedded.1"}]}"#)
+            .create_async()
+            .await;
+        let reset = server
+            .mock(
+                "POST",
+                "/redfish/v1/Systems/System.Embedded.1/Actions/ComputerSystem.Reset",
+            )
+            .match_body(Matcher::Json(json!({ "ResetType": "On" })))
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let result = power_on(&config(&server.url(), None)).await;
+        assert!(result.is_ok(), "{:?}", result);
+        systems.assert_async().await;
+        reset.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_power_on_failure() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/redfish/v1/Systems/1/Actions/ComputerSystem.Reset")
+            .with_status(401)
+            .with_body("Unauthorized")
+            .create_async()
+            .await;
+
+        let result = power_on(&config(&server.url(), Some("/redfish/v1/Systems/1"))).await;
+        assert!(matches!(
+            result,
+            Err(RedfishError::ActionFailed(_, 401, body)) if body == "Unauthorized"
+        ));
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/redfish/v1/Systems")
+            .with_status(200)
+            .with_body(r#"{"Members": []}"#)
+            .create_async()
+            .await;
+
+        let result = power_on(&config(&server.url(), None)).await;
+        assert!(matches!(result, Err(RedfishError::NoSystem(_))));
+    }
+
+    #[test]
+    fn test_invalid_url() {
+        assert!(config("https://10.0.0.5", None).validate().is_ok());
+        assert!(matches!(
+            config("10.0.0.5", None).validate(),
+            Err(RedfishError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            config("ftp://10.0.0.5", None).validate(),
+            Err(RedfishError::InvalidUrl(_))
+        ));
+    }
+}
@@ -1,18 +1,29 @@
 use colored::Colorize;
+use pnet::util::MacAddr;
 use regex::Regex;
 use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
-    fmt, fs,
-    net::IpAddr,
-    process::Stdio,
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
     sync::Arc,
     time::Instant,
 };
-use tokio::{net::TcpStream, process::Command, sync::RwLock};
+use tokio::{net::TcpStream, sync::RwLock};
 
 use thiserror::Error;
 
+use crate::config;
+use crate::diagnostics::{self, Diagnostic, Segment};
+use crate::http_power;
+use crate::interpolation::InterpolationError;
+use crate::ipmi;
+use crate::redfish;
+use crate::shell;
+use crate::transport::WakeTarget;
+use crate::wol;
+
 #[derive(Debug, Error)]
 pub enum ServerConfigError {
     #[error("Failed to parse config file: {0}")]
@@ -21,13 +32,34 @@ pub enum ServerConfigError {
     #[error("Found undefined dependency: {0}")]
     UndefinedDependency(String),
 
+    #[error("No servers are tagged with {0}")]
+    UndefinedTag(String),
+
     #[error("Found circular dependency: {0}")]
     CircularDependency(String),
 
     #[error("Misconfigured healthcheck: {0}")]
     BadHealthCheckDefinition(String),
+
+    #[error("Misconfigured wake for {0}: {1}")]
+    BadWakeDefinition(String, String),
+
+    #[error("Misconfigured shutdown for {0}: {1}")]
+    BadShutdownDefinition(String, String),
+
+    #[error("Server {0} is already defined at {1}")]
+    DuplicateServer(String, String),
+
+    #[error("Failed to interpolate {0}: {1}")]
+    BadInterpolation(String, InterpolationError),
+
+    #[error("{}", diagnostics::render(.0))]
+    Invalid(Vec<Diagnostic>),
 }
 
+// How long a check may take when checking whether a server is already up
+const PRECHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 fn default_retry_duration() -> std::time::Duration {
     std::time::Duration::from_secs(10)
 }
@@ -38,6 +70,23 @@ fn default_timeout_duration() -> std::time::Duration {
     std::time::Duration::from_secs(300)
 }
 
+fn default_wol_repeat_count() -> u32 {
+    1
+}
+
+fn default_wol_repeat_interval() -> std::time::Duration {
+    std::time::Duration::from_secs(1)
+}
+
+fn default_udp_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::BROADCAST)
+}
+
+fn default_udp_port() -> u16 {
+    // "discard" port, the conventional port for WOL over UDP
+    9
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub enum CheckStatus {
     #[default]
@@ -58,13 +107,26 @@ pub struct HealthCheck {
     #[serde(flatten)]
     pub method: HealthCheckMethod,
 
+    /// Pass once the check fails instead, for checking that a server is down
+    #[serde(skip)]
+    pub until_down: bool,
+
+    /// Values interpolated from secret files or variables, hidden when the check is displayed
+    #[serde(skip)]
+    pub secrets: Vec<String>,
+
     #[serde(skip)]
     pub status: CheckStatus,
 }
 
 impl fmt::Display for HealthCheck {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.method)
+        let method = self.method.redacted(&self.secrets);
+        if self.until_down {
+            write!(f, "{} {}", "down:".bold(), method)
+        } else {
+            write!(f, "{}", method)
+        }
     }
 }
 
@@ -87,6 +149,31 @@ pub enum HealthCheckMethod {
         #[serde(default, with = "serde_regex")]
         regex: Option<Regex>,
     },
+    /// Passes once the BMC reports the chassis as powered on
+    Ipmi(ipmi::IpmiConfig),
+}
+
+const REDACTED: &str = "***";
+
+/// Replace every secret in `text` with `***`
+pub fn redact(text: &mut String, secrets: &[String]) {
+    for secret in secrets.iter().filter(|secret| !secret.is_empty()) {
+        *text = text.replace(secret.as_str(), REDACTED);
+    }
+}
+
+impl HealthCheckMethod {
+    /// A copy with `secrets` hidden in the fields that are displayed, before they are truncated
+    fn redacted(&self, secrets: &[String]) -> HealthCheckMethod {
+        let mut method = self.clone();
+        match &mut method {
+            HealthCheckMethod::Http { url, .. } => redact(url, secrets),
+            HealthCheckMethod::Port { ip, .. } => redact(ip, secrets),
+            HealthCheckMethod::Shell { command, .. } => redact(command, secrets),
+            HealthCheckMethod::Ipmi(config) => redact(&mut config.host, secrets),
+        }
+        method
+    }
 }
 
 fn truncate_command(command: &str, max_length: usize) -> String {
@@ -114,15 +201,74 @@ impl fmt::Display for HealthCheckMethod {
                 status: _,
                 regex: _,
             } => write!(f, "{} [{}]", "shell".bold(), truncate_command(command, 30)),
+            HealthCheckMethod::Ipmi(config) => write!(f, "{} [{}]", "ipmi".bold(), config.host),
+        }
+    }
+}
+
+/// How many magic packets to send each time a server is woken up
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct WolRepeat {
+    #[serde(default = "default_wol_repeat_count")]
+    pub count: u32,
+    #[serde(default = "default_wol_repeat_interval", with = "humantime_serde")]
+    pub interval: std::time::Duration,
+}
+
+impl Default for WolRepeat {
+    fn default() -> Self {
+        WolRepeat {
+            count: default_wol_repeat_count(),
+            interval: default_wol_repeat_interval(),
         }
     }
 }
 
+/// How a server is powered on
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WakeMethod {
+    /// Magic packet to the server's `mac`, using the WOL settings of the server
+    #[default]
+    Wol,
+    /// `ComputerSystem.Reset` through the BMC
+    Redfish(redfish::RedfishConfig),
+    /// Chassis power on through the BMC with IPMI over LAN
+    Ipmi(ipmi::IpmiConfig),
+    /// HTTP request to a smart plug or switched PDU
+    HttpPower(http_power::HttpPowerConfig),
+    /// Custom command, run with the server fields in `RALLYUP_*` environment variables
+    Shell(shell::ShellConfig),
+}
+
+/// How a server is shut down by `rallyup down`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShutdownMethod {
+    /// Custom command, run with the server fields in `RALLYUP_*` environment variables
+    Shell(shell::ShellConfig),
+    /// Command run on the server over SSH
+    Ssh(shell::SshConfig),
+    /// `ComputerSystem.Reset` through the BMC, `GracefulShutdown` unless `reset_type` is set
+    Redfish(redfish::RedfishConfig),
+    /// Soft (ACPI) shutdown through the BMC with IPMI over LAN
+    Ipmi(ipmi::IpmiConfig),
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum ServerStatus {
     #[default]
     Waiting,
     WOLSent,
+    PowerOnSent,
+    /// All checks passed before the server was woken up, so it was left alone
+    AlreadyUp,
+    ShutdownSent,
+    /// All "is down" checks passed after shutting the server down
+    Down,
+    /// Has no `shutdown` action, or a server depending on it was left running, so `rallyup down`
+    /// left it running
+    LeftRunning,
     Ok,
     TimedOut,
 }
@@ -130,26 +276,236 @@ pub enum ServerStatus {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
     pub name: String,
-    pub mac: String,
-    pub interface: String,
     #[serde(default)]
-    pub vlan: Option<u16>,
+    pub wake: WakeMethod,
+
+    /// Required when waking the server with WOL, unless it can be looked up from `ip` in the MAC cache
+    #[serde(default)]
+    pub mac: Option<wol::MacAddress>,
+    /// Interface to send raw Ethernet WOL frames from, selected automatically when omitted
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// IP address of the server, used to select an interface when `interface` is omitted
+    /// and to look up the MAC when `mac` is omitted
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub vlan: Option<wol::Vlan>,
+
+    #[serde(default)]
+    pub password: Option<wol::SecureOnPassword>,
+
+    /// Ethernet destination of the WOL frame when using the Ethernet transport
+    #[serde(default)]
+    pub destination: wol::Destination,
+
+    #[serde(default)]
+    pub transport: wol::Transport,
+    /// Destination of the UDP datagram when using the UDP transport.
+    /// Can be the limited broadcast, a directed subnet broadcast, a unicast address,
+    /// or an IPv6 address such as the all-nodes multicast address `ff02::1`.
+    #[serde(default = "default_udp_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_udp_port")]
+    pub port: u16,
+
+    #[serde(default)]
+    pub wol_repeat: WolRepeat,
+    /// Re-send the magic packets at this interval while the health checks are still failing
+    #[serde(default, with = "humantime_serde")]
+    pub rewake_every: Option<std::time::Duration>,
 
+    /// Groups the server belongs to, other servers can depend on all of them with `tag:<name>`
+    #[serde(default)]
+    pub tags: Vec<String>,
     #[serde(default)]
     pub depends: Vec<String>,
     #[serde(default)]
     pub check: Vec<HealthCheck>,
 
+    #[serde(default)]
+    pub shutdown: Option<ShutdownMethod>,
+    /// Checks that pass once the server is down, e.g. a port check passes once the port is closed
+    #[serde(default)]
+    pub down_check: Vec<HealthCheck>,
+
+    /// Config file the server was read from
+    #[serde(skip)]
+    pub source: Option<config::Source>,
+    /// Values interpolated from secret files or variables into any of the fields, e.g. the
+    /// password of a wake method, hidden in errors
+    #[serde(skip)]
+    pub secrets: Vec<String>,
     #[serde(skip)]
     pub status: ServerStatus,
+    #[serde(skip)]
+    pub packets_sent: u32,
+}
+
+impl Server {
+    /// Raw Ethernet frames and link-local IPv6 destinations have to go out of a specific interface
+    pub fn requires_interface(&self) -> bool {
+        if !matches!(self.wake, WakeMethod::Wol) {
+            return false;
+        }
+        match self.transport {
+            wol::Transport::Ethernet => true,
+            wol::Transport::Udp => wol::requires_interface(self.address),
+        }
+    }
+
+    pub fn wake_target(&self) -> WakeTarget {
+        WakeTarget {
+            name: self.name.clone(),
+            // Servers woken up with WOL have their MAC filled in from the cache before waking up
+            mac: self.mac.map(|mac| mac.addr()).unwrap_or_else(MacAddr::zero),
+            destination: self.destination,
+            transport: self.transport,
+            interface: self.interface.clone(),
+            vlan: self.vlan,
+            password: self.password.clone(),
+            address: self.address,
+            port: self.port,
+        }
+    }
+
+    /// A copy of `text` with the secrets of the server hidden
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        redact(&mut text, &self.secrets);
+        text
+    }
+
+    /// Whether `other` is one of the servers this one depends on, by name or through a tag
+    pub fn depends_on(&self, other: &Server) -> bool {
+        other.name != self.name
+            && self
+                .depends
+                .iter()
+                .any(|dep| match dep.strip_prefix(TAG_PREFIX) {
+                    Some(tag) => other.tags.iter().any(|other_tag| other_tag == tag),
+                    None => *dep == other.name,
+                })
+    }
+
+    /// Replace the health checks with the "is down" checks, for shutting the server down
+    pub fn use_down_checks(&mut self) {
+        self.check = std::mem::take(&mut self.down_check)
+            .into_iter()
+            .map(|check| HealthCheck {
+                until_down: true,
+                ..check
+            })
+            .collect();
+    }
+
+    /// Fields of the server exposed to wake and shutdown commands, unset fields are left out
+    pub fn environment(&self) -> Vec<(String, String)> {
+        let mut env = vec![("RALLYUP_NAME".to_string(), self.name.clone())];
+        if let Some(mac) = &self.mac {
+            env.push(("RALLYUP_MAC".to_string(), mac.to_string()));
+        }
+        if let Some(ip) = self.ip {
+            env.push(("RALLYUP_IP".to_string(), ip.to_string()));
+        }
+        if let Some(interface) = &self.interface {
+            env.push(("RALLYUP_INTERFACE".to_string(), interface.clone()));
+        }
+        if let Some(vlan) = &self.vlan {
+            env.push(("RALLYUP_VLAN".to_string(), vlan.tag.id.to_string()));
+        }
+        env
+    }
+}
+
+/// Report every server sharing its name with a server defined before it
+fn check_duplicate_names(config: &config::Config, diagnostics: &mut Vec<Diagnostic>) {
+    let mut seen: HashMap<&str, &Server> = HashMap::new();
+    for server in &config.servers {
+        if let Some(first) = seen.get(server.name.as_str()) {
+            let error =
+                ServerConfigError::DuplicateServer(server.name.clone(), config.describe(first));
+            diagnostics.push(config.diagnostic(server, &[Segment::key("name")], error));
+        } else {
+            seen.insert(&server.name, server);
+        }
+    }
+}
+
+/// Report every `depends` entry naming a server, or a tag, that does not exist
+fn check_dependencies(config: &config::Config, diagnostics: &mut Vec<Diagnostic>) {
+    let server_from_name = map_server_names(&config.servers);
+    let servers_from_tag = map_server_tags(&config.servers);
+
+    for server in &config.servers {
+        for (index, dep) in server.depends.iter().enumerate() {
+            let error = match dep.strip_prefix(TAG_PREFIX) {
+                // The tag may be on one of the servers that could not be read
+                Some(tag)
+                    if !servers_from_tag.contains_key(tag)
+                        && !config.invalid_tags.contains(tag) =>
+                {
+                    ServerConfigError::UndefinedTag(tag.to_string())
+                }
+                None if !server_from_name.contains_key(dep) && !config.invalid.contains(dep) => {
+                    ServerConfigError::UndefinedDependency(dep.clone())
+                }
+                _ => continue,
+            };
+            let path = [Segment::key("depends"), Segment::Index(index)];
+            diagnostics.push(config.diagnostic(server, &path, error));
+        }
+    }
 }
 
 fn map_server_names(servers: &[Server]) -> HashMap<String, &Server> {
     servers.iter().map(|s| (s.name.clone(), s)).collect()
 }
 
+fn map_server_tags(servers: &[Server]) -> HashMap<String, Vec<&Server>> {
+    let mut servers_from_tag: HashMap<String, Vec<&Server>> = HashMap::new();
+    for server in servers {
+        for tag in &server.tags {
+            servers_from_tag
+                .entry(tag.clone())
+                .or_default()
+                .push(server);
+        }
+    }
+    servers_from_tag
+}
+
+/// Prefix of `depends` entries that depend on every server with a tag
+const TAG_PREFIX: &str = "tag:";
+
+/// The servers `server` depends on through the `dep` entry, either a server name or `tag:<name>`
+fn expand_dependency<'a>(
+    server: &Server,
+    dep: &str,
+    server_from_name: &HashMap<String, &'a Server>,
+    servers_from_tag: &HashMap<String, Vec<&'a Server>>,
+) -> Result<Vec<&'a Server>, ServerConfigError> {
+    let Some(tag) = dep.strip_prefix(TAG_PREFIX) else {
+        let dep_server = server_from_name
+            .get(dep)
+            .ok_or_else(|| ServerConfigError::UndefinedDependency(dep.to_string()))?;
+        return Ok(vec![dep_server]);
+    };
+
+    let tagged = servers_from_tag
+        .get(tag)
+        .ok_or_else(|| ServerConfigError::UndefinedTag(tag.to_string()))?;
+    // A server in the group it depends on only waits for the rest of the group
+    Ok(tagged
+        .iter()
+        .filter(|tagged| tagged.name != server.name)
+        .copied()
+        .collect())
+}
+
 fn determine_wakeup_order(servers: &[Server]) -> Result<Vec<Server>, ServerConfigError> {
     let server_from_name = map_server_names(servers);
+    let servers_from_tag = map_server_tags(servers);
 
     let mut visited = HashSet::new();
     let mut visiting = HashSet::new();
@@ -160,6 +516,7 @@ fn determine_wakeup_order(servers: &[Server]) -> Result<Vec<Server>, ServerConfi
             depth_first_search(
                 server,
                 &server_from_name,
+                &servers_from_tag,
                 &mut visited,
                 &mut visiting,
                 &mut sorted,
@@ -178,6 +535,7 @@ fn determine_wakeup_order(servers: &[Server]) -> Result<Vec<Server>, ServerConfi
 fn depth_first_search(
     server: &Server,
     server_from_name: &HashMap<String, &Server>,
+    servers_from_tag: &HashMap<String, Vec<&Server>>,
     visited: &mut HashSet<String>,
     visiting: &mut HashSet<String>,
     sorted: &mut Vec<String>,
@@ -193,10 +551,16 @@ fn depth_first_search(
     visiting.insert(server.name.clone());
 
     for dep in &server.depends {
-        let dep_server = server_from_name
-            .get(dep)
-            .ok_or_else(|| ServerConfigError::UndefinedDependency(dep.clone()))?;
-        depth_first_search(dep_server, server_from_name, visited, visiting, sorted)?;
+        for dep_server in expand_dependency(server, dep, server_from_name, servers_from_tag)? {
+            depth_first_search(
+                dep_server,
+                server_from_name,
+                servers_from_tag,
+                visited,
+                visiting,
+                sorted,
+            )?;
+        }
     }
 
     visiting.remove(&server.name);
@@ -234,29 +598,124 @@ fn validate_health_check(healthcheck: &HealthCheckMethod) -> Result<(), ServerCo
                 return Err(ServerConfigError::BadHealthCheckDefinition("Health check via shell command requires an return code to match and/or a Regex to match in the standard output".into()));
             }
         }
+        HealthCheckMethod::Ipmi(config) => {
+            if let Err(e) = config.validate() {
+                return Err(ServerConfigError::BadHealthCheckDefinition(e.to_string()));
+            }
+        }
     }
 
     Ok(())
 }
 
-pub fn parse_server_dependencies(file_path: &str) -> Result<Vec<Server>, ServerConfigError> {
-    let yaml_content =
-        fs::read_to_string(file_path).map_err(|e| ServerConfigError::ParseError(e.to_string()))?;
+fn validate_wol_repeat(server: &Server) -> Result<(), ServerConfigError> {
+    if server.wol_repeat.count == 0 {
+        return Err(ServerConfigError::BadWakeDefinition(
+            server.name.clone(),
+            "wol_repeat count must be at least 1".into(),
+        ));
+    }
+    if server.rewake_every == Some(std::time::Duration::ZERO) {
+        return Err(ServerConfigError::BadWakeDefinition(
+            server.name.clone(),
+            "rewake_every must be longer than 0s".into(),
+        ));
+    }
+    Ok(())
+}
 
-    let servers: Vec<Server> = serde_yaml_ng::from_str(&yaml_content)
-        .map_err(|e| ServerConfigError::ParseError(e.to_string()))?;
+fn validate_wake(server: &Server) -> Result<(), ServerConfigError> {
+    let bad_wake =
+        |reason: String| ServerConfigError::BadWakeDefinition(server.name.clone(), reason);
 
-    for server in &servers {
-        for healthcheck in &server.check {
-            validate_health_check(&healthcheck.method)?;
+    match &server.wake {
+        WakeMethod::Wol => {
+            if server.mac.is_none() && server.ip.is_none() {
+                return Err(bad_wake(
+                    "waking up with WOL requires a `mac`, or an `ip` to look it up".into(),
+                ));
+            }
+            validate_wol_repeat(server)
+        }
+        WakeMethod::Redfish(config) => config.validate().map_err(|e| bad_wake(e.to_string())),
+        WakeMethod::Ipmi(config) => config.validate().map_err(|e| bad_wake(e.to_string())),
+        WakeMethod::HttpPower(config) => config.validate().map_err(|e| bad_wake(e.to_string())),
+        WakeMethod::Shell(config) => config.validate().map_err(|e| bad_wake(e.to_string())),
+    }
+}
+
+fn validate_shutdown(server: &Server) -> Result<(), ServerConfigError> {
+    let bad_shutdown =
+        |reason: String| ServerConfigError::BadShutdownDefinition(server.name.clone(), reason);
+
+    match &server.shutdown {
+        None => Ok(()),
+        Some(ShutdownMethod::Shell(config)) => {
+            config.validate().map_err(|e| bad_shutdown(e.to_string()))
+        }
+        Some(ShutdownMethod::Ssh(config)) => {
+            config.validate().map_err(|e| bad_shutdown(e.to_string()))
+        }
+        Some(ShutdownMethod::Redfish(config)) => {
+            config.validate().map_err(|e| bad_shutdown(e.to_string()))
+        }
+        Some(ShutdownMethod::Ipmi(config)) => {
+            config.validate().map_err(|e| bad_shutdown(e.to_string()))
+        }
+    }
+}
+
+pub fn parse_server_dependencies(
+    file_path: &str,
+    format: Option<config::Format>,
+) -> Result<Vec<Server>, ServerConfigError> {
+    // Servers can be spread across the config file and the files it includes. Every problem is
+    // collected, so that they can all be fixed at once
+    let mut config = config::load_servers(Path::new(file_path), format);
+    let mut diagnostics = std::mem::take(&mut config.diagnostics);
+    check_duplicate_names(&config, &mut diagnostics);
+
+    for server in &config.servers {
+        if let Err(e) = validate_wake(server) {
+            diagnostics.push(config.diagnostic(server, &[Segment::key("wake")], e));
+        }
+        if let Err(e) = validate_shutdown(server) {
+            diagnostics.push(config.diagnostic(server, &[Segment::key("shutdown")], e));
+        }
+        for (list, checks) in [("check", &server.check), ("down_check", &server.down_check)] {
+            for (index, healthcheck) in checks.iter().enumerate() {
+                if let Err(e) = validate_health_check(&healthcheck.method) {
+                    let path = [Segment::key(list), Segment::Index(index)];
+                    diagnostics.push(config.diagnostic(server, &path, e));
+                }
+            }
         }
     }
+    check_dependencies(&config, &mut diagnostics);
 
-    // Apply topological sort to determine order to wake the servers
-    // check for circular and undefined servers along the way
-    let sorted = determine_wakeup_order(&servers)?;
+    if !diagnostics.is_empty() {
+        // Shown in the order they appear in, to be read along with the files
+        diagnostics.sort_by_key(|d| (d.file.clone(), d.position.map(|p| (p.line, p.column))));
+        return Err(ServerConfigError::Invalid(diagnostics));
+    }
 
-    Ok(sorted)
+    // Apply topological sort to determine order to wake the servers
+    // check for circular servers along the way
+    determine_wakeup_order(&config.servers).map_err(|e| {
+        let server = match &e {
+            ServerConfigError::CircularDependency(name) => {
+                config.servers.iter().find(|server| &server.name == name)
+            }
+            _ => None,
+        };
+        match server {
+            Some(server) => {
+                let diagnostic = config.diagnostic(server, &[Segment::key("depends")], e);
+                ServerConfigError::Invalid(vec![diagnostic])
+            }
+            None => e,
+        }
+    })
 }
 
 async fn http_health_check(
@@ -293,13 +752,7 @@ async fn shell_health_check(
     expected_status: Option<i32>,
     payload_regex: Option<Regex>,
 ) -> bool {
-    let result = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await;
+    let result = shell::run(command, &[]).await;
 
     if let Ok(output) = result {
         if let Some(status) = expected_status {
@@ -330,7 +783,68 @@ pub async fn check_health(check: HealthCheckMethod) -> bool {
             status,
             regex,
         } => shell_health_check(&command, status, regex).await,
+        HealthCheckMethod::Ipmi(config) => ipmi::is_powered_on(&config).await.unwrap_or(false),
+    }
+}
+
+/// Run every check of a server once, without retrying, and mark them as passed if they all pass.
+///
+/// Servers without checks never pass, since there is nothing to tell.
+async fn run_checks_once(servers: &Arc<RwLock<Vec<Server>>>, index: usize) -> bool {
+    let checks = servers.read().await[index].check.clone();
+    if checks.is_empty() {
+        return false;
+    }
+
+    let tasks: Vec<_> = checks
+        .into_iter()
+        .map(|check| {
+            // Checks that hang, e.g. connecting to a port that drops packets, count as failed
+            let timeout = check.timeout.min(PRECHECK_TIMEOUT);
+            tokio::spawn(async move {
+                let healthy = tokio::time::timeout(timeout, check_health(check.method))
+                    .await
+                    .unwrap_or(false);
+                healthy != check.until_down
+            })
+        })
+        .collect();
+
+    let mut passed = true;
+    for task in tasks {
+        passed &= task.await.unwrap_or(false);
+    }
+
+    if passed {
+        for check in servers.write().await[index].check.iter_mut() {
+            check.status = CheckStatus::Ok;
+        }
+    }
+    passed
+}
+
+/// Run every check of a server once, without retrying, and mark it as [`ServerStatus::AlreadyUp`]
+/// if they all pass.
+///
+/// Servers without checks are never considered up, since there is nothing to tell.
+pub async fn precheck_server(servers: Arc<RwLock<Vec<Server>>>, index: usize) -> bool {
+    let up = run_checks_once(&servers, index).await;
+    if up {
+        servers.write().await[index].status = ServerStatus::AlreadyUp;
     }
+    up
+}
+
+/// Run the "is down" checks of a server once, and mark it as [`ServerStatus::Down`] if they all
+/// pass, so that servers that are already off are not shut down again.
+///
+/// Expects the checks to have been replaced with [`Server::use_down_checks`].
+pub async fn precheck_server_down(servers: Arc<RwLock<Vec<Server>>>, index: usize) -> bool {
+    let down = run_checks_once(&servers, index).await;
+    if down {
+        servers.write().await[index].status = ServerStatus::Down;
+    }
+    down
 }
 
 pub async fn perform_health_checks(
@@ -361,7 +875,7 @@ pub async fn perform_health_checks(
                     }
                     return CheckStatus::TimedOut;
                 }
-                if check_health(check.method.clone()).await {
+                if check_health(check.method.clone()).await != check.until_down {
                     break;
                 } else {
                     tokio::time::sleep(check.retry).await;
@@ -398,6 +912,8 @@ pub async fn perform_health_checks(
 }
 
 #[cfg(test)]
+// The health check tests pass their `&str` arguments by reference
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
 
@@ -429,7 +945,7 @@ mod tests {
           check: []
 
         - name: "server4"
-          mac: "FF:EE:DD:CC:BB:AA"
+          mac: "FE:EE:DD:CC:BB:AA"
           interface: "eth1"
           vlan: 200
           depends:
@@ -583,79 +1099,1053 @@ mod tests {
     }
 
     #[test]
-    fn test_determine_wakeup_order() {
-        // Define the YAML string for servers with dependencies
+    fn test_udp_transport() {
         let yaml_data = r#"
-        - name: "server_a"
+        - name: "server1"
           mac: "00:11:22:33:44:55"
           interface: "eth0"
-          depends:
-            - "server_b"
-            - "server_c"
 
-        - name: "server_b"
-          mac: "11:22:33:44:55:66"
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
           interface: "eth0"
-          depends:
-            - "server_c"
+          transport: udp
 
-        - name: "server_c"
-          mac: "22:33:44:55:66:77"
+        - name: "server3"
+          mac: "AA:BB:CC:DD:EE:FF"
+          interface: "eth0"
+          transport: udp
+          address: 192.168.1.255
+          port: 7
+
+        - name: "server4"
+          mac: "00:11:22:33:44:66"
           interface: "eth0"
+          transport: udp
+          address: "ff02::1"
         "#;
 
-        // Parse the YAML string into the expected structure
         let servers: Vec<Server> =
             serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
 
-        // Expected topologically sorted order (server_c first, then server_b, then server_a)
-        let expected_order = vec!["server_c", "server_b", "server_a"];
+        assert_eq!(servers[0].transport, wol::Transport::Ethernet);
 
-        // Call the function to get the wakeup order
-        let result = determine_wakeup_order(&servers).expect("Failed to determine wakeup order");
+        assert_eq!(servers[1].transport, wol::Transport::Udp);
+        assert_eq!(
+            servers[1].address,
+            "255.255.255.255".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(servers[1].port, 9);
 
-        // Check that the result matches the expected order
+        assert_eq!(servers[2].transport, wol::Transport::Udp);
         assert_eq!(
-            result.into_iter().map(|s| s.name).collect::<Vec<String>>(),
-            expected_order
+            servers[2].address,
+            "192.168.1.255".parse::<IpAddr>().unwrap()
         );
+        assert_eq!(servers[2].port, 7);
+
+        assert!(servers[0].requires_interface());
+        assert!(!servers[1].requires_interface());
+        assert!(!servers[2].requires_interface());
+        assert!(servers[3].requires_interface());
     }
 
-    #[tokio::test]
-    async fn test_http_health_check_success() {
-        let mut server = mockito::Server::new_async().await;
-        server
-            .mock("GET", "/health")
-            .with_status(200)
-            .with_body("healthy")
-            .create_async()
-            .await;
+    #[test]
+    fn test_destination() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
 
-        let url = format!("{}/health", server.url());
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          interface: "eth0"
+          destination: unicast
+        "#;
 
-        // Status and regex match
-        let status = Some(200);
-        let regex = Some(Regex::new("health").unwrap());
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(result);
+        assert_eq!(wol::Destination::Broadcast, servers[0].destination);
+        assert_eq!(
+            wol::Destination::Unicast,
+            servers[1].wake_target().destination
+        );
 
-        // Just status
-        let status = Some(200);
-        let regex = None;
+        let result: Result<Server, _> = serde_yaml_ng::from_str(
+            r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        destination: multicast
+        "#,
+        );
+        assert!(
+            result.is_err(),
+            "Expected an unknown destination to be rejected"
+        );
+    }
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(result);
+    #[test]
+    fn test_secureon_password() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        password: "01:02:03:04"
+        "#;
 
-        // Just regex
-        let status = None;
-        let regex = Some(Regex::new("health").unwrap());
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        assert_eq!(
+            server.password.expect("password should be set").bytes(),
+            &[0x01, 0x02, 0x03, 0x04]
+        );
 
-        let result = http_health_check(&url, status, regex).await;
-        assert!(result);
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        interface: "eth0"
+        password: "01:02:03"
+        "#;
+
+        let result: Result<Server, _> = serde_yaml_ng::from_str(yaml_data);
+        assert!(result.is_err(), "Expected a 3 byte password to be rejected");
     }
 
-    #[tokio::test]
+    #[test]
+    fn test_mac_normalisation() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00-1A-2B-3C-4D-5E"
+          interface: "eth0"
+
+        - name: "server2"
+          mac: "001a.2b3c.4d5f"
+          interface: "eth0"
+
+        - name: "server3"
+          mac: "001A2B3C4D60"
+          interface: "eth0"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        let macs: Vec<String> = servers
+            .iter()
+            .map(|server| server.mac.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "00:1a:2b:3c:4d:5e",
+                "00:1a:2b:3c:4d:5f",
+                "00:1a:2b:3c:4d:60"
+            ],
+            macs
+        );
+        assert_eq!(
+            MacAddr::new(0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5F),
+            servers[1].wake_target().mac
+        );
+
+        for mac in ["ff:ff:ff:ff:ff:ff", "01:00:5e:00:00:fb", "00:11:22:33:44"] {
+            let yaml_data = format!(
+                r#"
+            name: "server1"
+            mac: "{}"
+            interface: "eth0"
+            "#,
+                mac
+            );
+            let result: Result<Server, _> = serde_yaml_ng::from_str(&yaml_data);
+            let error = result
+                .expect_err("Expected the MAC to be rejected")
+                .to_string();
+            assert!(error.contains("Invalid MAC address"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_wol_repeat() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          interface: "eth0"
+          wol_repeat:
+            count: 3
+            interval: 500ms
+          rewake_every: 30s
+
+        - name: "server3"
+          mac: "AA:BB:CC:DD:EE:FF"
+          interface: "eth0"
+          wol_repeat:
+            count: 0
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert_eq!(servers[0].wol_repeat, WolRepeat::default());
+        assert_eq!(servers[0].rewake_every, None);
+        assert!(validate_wol_repeat(&servers[0]).is_ok());
+
+        assert_eq!(servers[1].wol_repeat.count, 3);
+        assert_eq!(
+            servers[1].wol_repeat.interval,
+            std::time::Duration::from_millis(500)
+        );
+        assert_eq!(
+            servers[1].rewake_every,
+            Some(std::time::Duration::from_secs(30))
+        );
+        assert!(validate_wol_repeat(&servers[1]).is_ok());
+
+        assert!(matches!(
+            validate_wol_repeat(&servers[2]),
+            Err(ServerConfigError::BadWakeDefinition(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_optional_interface() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          ip: 192.168.1.10
+
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          interface: "eth1"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert_eq!(servers[0].interface, None);
+        assert_eq!(servers[0].ip, Some("192.168.1.10".parse().unwrap()));
+        assert_eq!(servers[1].interface, Some("eth1".to_string()));
+        assert_eq!(servers[1].ip, None);
+    }
+
+    #[test]
+    fn test_wake_methods() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+
+        - name: "server2"
+          wake:
+            type: redfish
+            url: https://10.0.0.5
+            username: admin
+            password: secret
+            insecure: true
+
+        - name: "server3"
+          interface: "eth0"
+
+        - name: "server4"
+          wake:
+            type: redfish
+            url: 10.0.0.6
+            username: admin
+            password: secret
+
+        - name: "server5"
+          wake:
+            type: ipmi
+            host: 10.0.0.7
+            username: ADMIN
+            password: ADMIN
+            privilege: operator
+
+        - name: "server6"
+          wake:
+            type: http_power
+            url: http://10.0.0.20/cm?cmnd=Power%20On
+            headers:
+              X-Api-Key: token
+
+        - name: "server7"
+          mac: "00:11:22:33:44:77"
+          ip: 192.168.1.17
+          vlan: 20
+          wake:
+            type: shell
+            command: ssh router etherwake -i br0 $RALLYUP_MAC
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert!(matches!(servers[0].wake, WakeMethod::Wol));
+        assert!(validate_wake(&servers[0]).is_ok());
+
+        match &servers[1].wake {
+            WakeMethod::Redfish(config) => {
+                assert_eq!(config.url, "https://10.0.0.5");
+                assert_eq!(config.reset_type, None);
+                assert!(config.insecure);
+            }
+            _ => panic!("Expected a Redfish wake method"),
+        }
+        assert!(validate_wake(&servers[1]).is_ok());
+        assert!(!servers[1].requires_interface());
+
+        match &servers[4].wake {
+            WakeMethod::Ipmi(config) => {
+                assert_eq!("10.0.0.7", config.host);
+                assert_eq!(ipmi::Privilege::Operator, config.privilege);
+            }
+            _ => panic!("Expected an IPMI wake method"),
+        }
+        assert!(validate_wake(&servers[4]).is_ok());
+        assert!(!servers[4].requires_interface());
+
+        match &servers[5].wake {
+            WakeMethod::HttpPower(config) => {
+                assert_eq!("GET", config.method);
+                assert_eq!(Some(&"token".to_string()), config.headers.get("X-Api-Key"));
+            }
+            _ => panic!("Expected an HTTP power wake method"),
+        }
+        assert!(validate_wake(&servers[5]).is_ok());
+        assert!(!servers[5].requires_interface());
+
+        match &servers[6].wake {
+            WakeMethod::Shell(config) => {
+                assert_eq!("ssh router etherwake -i br0 $RALLYUP_MAC", config.command);
+                assert_eq!(0, config.status);
+            }
+            _ => panic!("Expected a shell wake method"),
+        }
+        assert!(validate_wake(&servers[6]).is_ok());
+        assert!(!servers[6].requires_interface());
+        assert_eq!(
+            vec![
+                ("RALLYUP_NAME".to_string(), "server7".to_string()),
+                ("RALLYUP_MAC".to_string(), "00:11:22:33:44:77".to_string()),
+                ("RALLYUP_IP".to_string(), "192.168.1.17".to_string()),
+                ("RALLYUP_VLAN".to_string(), "20".to_string()),
+            ],
+            servers[6].environment()
+        );
+
+        // WOL without a MAC
+        assert!(matches!(
+            validate_wake(&servers[2]),
+            Err(ServerConfigError::BadWakeDefinition(name, _)) if name == "server3"
+        ));
+
+        // Not a URL
+        assert!(matches!(
+            validate_wake(&servers[3]),
+            Err(ServerConfigError::BadWakeDefinition(name, _)) if name == "server4"
+        ));
+    }
+
+    #[test]
+    fn test_ipmi_health_check() {
+        let yaml_data = r#"
+        name: "server1"
+        mac: "00:11:22:33:44:55"
+        check:
+          - type: ipmi
+            host: "10.0.0.5"
+            username: "admin"
+            password: "secret"
+            timeout: 2m
+          - type: ipmi
+            host: "10.0.0.6"
+            username: "a-user-name-longer-than-16-bytes"
+            password: "secret"
+        "#;
+
+        let server: Server = serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        match &server.check[0].method {
+            HealthCheckMethod::Ipmi(config) => {
+                assert_eq!("10.0.0.5", config.host);
+                assert_eq!(623, config.port);
+            }
+            _ => panic!("Expected an IPMI health check"),
+        }
+        assert_eq!(std::time::Duration::from_secs(120), server.check[0].timeout);
+        assert!(validate_health_check(&server.check[0].method).is_ok());
+        assert!(matches!(
+            validate_health_check(&server.check[1].method),
+            Err(ServerConfigError::BadHealthCheckDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_shutdown_methods() {
+        let yaml_data = r#"
+        - name: "server1"
+          mac: "00:11:22:33:44:55"
+          check:
+            - type: port
+              ip: "10.0.0.1"
+              port: 22
+          shutdown:
+            type: ssh
+            host: 10.0.0.1
+            user: root
+          down_check:
+            - type: port
+              ip: "10.0.0.1"
+              port: 22
+              timeout: 2m
+
+        - name: "server2"
+          mac: "66:77:88:99:AA:BB"
+          shutdown:
+            type: redfish
+            url: https://10.0.0.5
+            username: admin
+            password: secret
+
+        - name: "server3"
+          mac: "AA:BB:CC:DD:EE:FF"
+          shutdown:
+            type: ipmi
+            host: 10.0.0.6
+            username: "a-user-name-longer-than-16-bytes"
+            password: secret
+        "#;
+
+        let mut servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        assert!(matches!(
+            servers[0].shutdown,
+            Some(ShutdownMethod::Ssh(ref config)) if config.command == "sudo shutdown -h now"
+        ));
+        assert!(validate_shutdown(&servers[0]).is_ok());
+        assert!(matches!(
+            servers[1].shutdown,
+            Some(ShutdownMethod::Redfish(_))
+        ));
+        assert!(validate_shutdown(&servers[1]).is_ok());
+        assert!(matches!(
+            validate_shutdown(&servers[2]),
+            Err(ServerConfigError::BadShutdownDefinition(name, _)) if name == "server3"
+        ));
+
+        servers[0].use_down_checks();
+        assert_eq!(1, servers[0].check.len());
+        assert!(servers[0].check[0].until_down);
+        assert_eq!(
+            std::time::Duration::from_secs(120),
+            servers[0].check[0].timeout
+        );
+        assert!(servers[0].down_check.is_empty());
+    }
+
+    #[test]
+    fn test_determine_wakeup_order() {
+        // Define the YAML string for servers with dependencies
+        let yaml_data = r#"
+        - name: "server_a"
+          mac: "00:11:22:33:44:55"
+          interface: "eth0"
+          depends:
+            - "server_b"
+            - "server_c"
+
+        - name: "server_b"
+          mac: "10:22:33:44:55:66"
+          interface: "eth0"
+          depends:
+            - "server_c"
+
+        - name: "server_c"
+          mac: "22:33:44:55:66:77"
+          interface: "eth0"
+        "#;
+
+        // Parse the YAML string into the expected structure
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        // Expected topologically sorted order (server_c first, then server_b, then server_a)
+        let expected_order = vec!["server_c", "server_b", "server_a"];
+
+        // Call the function to get the wakeup order
+        let result = determine_wakeup_order(&servers).expect("Failed to determine wakeup order");
+
+        // Check that the result matches the expected order
+        assert_eq!(
+            result.into_iter().map(|s| s.name).collect::<Vec<String>>(),
+            expected_order
+        );
+    }
+
+    #[test]
+    fn test_tag_dependencies() {
+        let yaml_data = r#"
+        - name: "hypervisor"
+          mac: "00:11:22:33:44:55"
+          depends:
+            - "tag:storage"
+            - "firewall"
+
+        - name: "nas-1"
+          mac: "10:22:33:44:55:66"
+          tags: ["storage"]
+          depends:
+            - "firewall"
+
+        - name: "nas-2"
+          mac: "10:22:33:44:55:77"
+          tags: ["storage", "backup"]
+          depends:
+            - "tag:storage"
+
+        - name: "firewall"
+          mac: "22:33:44:55:66:77"
+        "#;
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+
+        let result = determine_wakeup_order(&servers).expect("Failed to determine wakeup order");
+        assert_eq!(
+            vec!["firewall", "nas-1", "nas-2", "hypervisor"],
+            result.iter().map(|s| s.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["storage", "backup"], result[2].tags);
+
+        let yaml_data = r#"
+        - name: "hypervisor"
+          mac: "00:11:22:33:44:55"
+          depends:
+            - "tag:storage"
+
+        - name: "nas-1"
+          mac: "10:22:33:44:55:66"
+          tags: ["nas"]
+        "#;
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        assert!(matches!(
+            determine_wakeup_order(&servers),
+            Err(ServerConfigError::UndefinedTag(tag)) if tag == "storage"
+        ));
+    }
+
+    /// Every problem found in an invalid config
+    fn invalid(result: Result<Vec<Server>, ServerConfigError>) -> Vec<Diagnostic> {
+        match result {
+            Err(ServerConfigError::Invalid(diagnostics)) => diagnostics,
+            result => panic!("Expected an invalid config, got {:?}", result),
+        }
+    }
+
+    /// Write `files` to a new directory for the config tests
+    fn config_dir(test: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rallyup-test-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_split_config() {
+        let dir = config_dir(
+            "split",
+            &[
+                (
+                    "rallyup.yaml",
+                    r#"
+defaults:
+  interface: "eth1"
+include:
+  - conf.d
+  - compute.yaml
+servers:
+  - name: "firewall"
+    mac: "00:11:22:33:44:55"
+"#,
+                ),
+                (
+                    "conf.d/10-storage.yaml",
+                    r#"
+- name: "storage"
+  mac: "00:11:22:33:44:66"
+  depends:
+    - "firewall"
+"#,
+                ),
+                (
+                    "conf.d/20-backup.yml",
+                    r#"
+defaults:
+  transport: udp
+servers:
+  - name: "backup"
+    mac: "00:11:22:33:44:77"
+    depends:
+      - "compute"
+"#,
+                ),
+                ("conf.d/README.md", "Not a config file"),
+                (
+                    "compute.yaml",
+                    r#"
+- name: "compute"
+  mac: "00:11:22:33:44:88"
+  interface: "eth0"
+  depends:
+    - "storage"
+"#,
+                ),
+            ],
+        );
+
+        let servers = parse_server_dependencies(dir.join("rallyup.yaml").to_str().unwrap(), None)
+            .expect("Failed to load split config");
+        assert_eq!(
+            vec!["firewall", "storage", "compute", "backup"],
+            servers.iter().map(|s| s.name.as_str()).collect::<Vec<_>>()
+        );
+
+        // Included files inherit the defaults of the file including them
+        let backup = &servers[3];
+        assert_eq!(
+            Some(dir.join("conf.d/20-backup.yml")),
+            backup.source.as_ref().map(|source| source.file.clone())
+        );
+        assert_eq!(Some("eth1".to_string()), backup.interface);
+        assert_eq!(wol::Transport::Udp, backup.transport);
+        assert_eq!(Some("eth0".to_string()), servers[2].interface);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_config_errors() {
+        let dir = config_dir(
+            "split-errors",
+            &[
+                (
+                    "duplicate.yaml",
+                    "include: [conf.d]\nservers:\n  - name: storage\n    mac: 00:11:22:33:44:55\n",
+                ),
+                (
+                    "conf.d/storage.yaml",
+                    "- name: storage\n  mac: 00:11:22:33:44:66\n",
+                ),
+                ("undefined.yaml", "include: [compute.yaml]\n"),
+                (
+                    "compute.yaml",
+                    "- name: compute\n  mac: 00:11:22:33:44:77\n  depends: [gpu]\n",
+                ),
+                ("loop.yaml", "include: [loop.yaml]\n"),
+                ("cycle-a.yaml", "include: [cycle-b.yaml]\n"),
+                ("cycle-b.yaml", "include: [cycle-a.yaml]\n"),
+            ],
+        );
+        let load = |name: &str| parse_server_dependencies(dir.join(name).to_str().unwrap(), None);
+
+        let result = invalid(load("duplicate.yaml"));
+        assert!(matches!(
+            &result[0],
+            Diagnostic {
+                error: ServerConfigError::DuplicateServer(name, first),
+                file: Some(file),
+                ..
+            } if name == "storage"
+                && first.ends_with("duplicate.yaml:3:5")
+                && file.ends_with("storage.yaml")
+        ));
+
+        let result = invalid(load("undefined.yaml"));
+        assert!(matches!(
+            &result[0],
+            Diagnostic {
+                error: ServerConfigError::UndefinedDependency(dep),
+                file: Some(file),
+                ..
+            } if dep == "gpu" && file.ends_with("compute.yaml")
+        ));
+
+        // Files that cannot be included are reported at the include entry
+        let result = invalid(load("loop.yaml"));
+        assert!(matches!(
+            &result[0],
+            Diagnostic {
+                error: ServerConfigError::ParseError(_),
+                file: Some(file),
+                position: Some(_),
+                ..
+            } if file.ends_with("loop.yaml")
+        ));
+        let result = invalid(load("cycle-a.yaml"));
+        assert_eq!(1, result.len());
+        assert!(matches!(
+            &result[0],
+            Diagnostic {
+                error: ServerConfigError::ParseError(message),
+                file: Some(file),
+                ..
+            } if message.starts_with("include cycle:") && file.ends_with("cycle-b.yaml")
+        ));
+        let ServerConfigError::ParseError(message) = &result[0].error else {
+            unreachable!()
+        };
+        let cycle: Vec<_> = message.split(" -> ").collect();
+        assert_eq!(3, cycle.len());
+        for (file, expected) in cycle
+            .iter()
+            .zip(["cycle-a.yaml", "cycle-b.yaml", "cycle-a.yaml"])
+        {
+            assert!(file.ends_with(expected), "{}", message);
+        }
+        assert_eq!(1, invalid(load("missing.yaml")).len());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_diamond_includes() {
+        let dir = config_dir(
+            "diamond",
+            &[
+                ("rallyup.yaml", "include: [storage.yaml, compute.yaml]\n"),
+                (
+                    "storage.yaml",
+                    "include: [common.yaml]\nservers:\n  - name: storage\n    mac: 00:11:22:33:44:66\n    depends: [firewall]\n",
+                ),
+                (
+                    "compute.yaml",
+                    "include: [common.yaml]\nservers:\n  - name: compute\n    mac: 00:11:22:33:44:77\n    depends: [firewall]\n",
+                ),
+                ("common.yaml", "- name: firewall\n  mac: 00:11:22:33:44:55\n"),
+            ],
+        );
+
+        // A file included from several places is read once, without reporting duplicates
+        let servers = parse_server_dependencies(dir.join("rallyup.yaml").to_str().unwrap(), None)
+            .expect("Failed to load diamond includes");
+        assert_eq!(
+            vec!["firewall", "storage", "compute"],
+            servers.iter().map(|s| s.name.as_str()).collect::<Vec<_>>()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_config_errors() {
+        let dir = config_dir(
+            "collect-errors",
+            &[(
+                "rallyup.yaml",
+                r#"servers:
+  - name: "storage"
+    mac: "ff:ff:ff:ff:ff:ff"
+    tags: ["nas"]
+  - name: "web"
+    mac: "00:11:22:33:44:55"
+    check:
+      - type: http
+        url: "http://10.0.0.1"
+  - name: "compute"
+    mac: "00:11:22:33:44:66"
+    depends: ["storage", "gpu", "tag:nas", "tag:gpus"]
+  - name: "web"
+    mac: "00:11:22:33:44:77"
+  - name: "backup"
+    mac: "00:11:22:33:44:88"
+    check:
+      - type: shell
+        command: "${RALLYUP_TEST_NO_SUCH_VARIABLE}"
+        status: 0
+"#,
+            )],
+        );
+        let file = dir.join("rallyup.yaml");
+        let result = parse_server_dependencies(file.to_str().unwrap(), None);
+        let rendered = result.as_ref().unwrap_err().to_string();
+        let diagnostics = invalid(result);
+
+        let summary: Vec<_> = diagnostics
+            .iter()
+            .map(|d| {
+                let position = d.position.unwrap();
+                (
+                    d.server.clone().unwrap(),
+                    position.line,
+                    position.column,
+                    d.file.clone().unwrap(),
+                )
+            })
+            .collect();
+        let file_name = file.display().to_string();
+        assert_eq!(
+            vec![
+                // Bad MAC, the servers depending on it, or on its tag, are not reported
+                ("storage".to_string(), 3, 5, file_name.clone()),
+                ("web".to_string(), 8, 9, file_name.clone()),
+                ("compute".to_string(), 12, 26, file_name.clone()),
+                ("compute".to_string(), 12, 44, file_name.clone()),
+                ("web".to_string(), 13, 5, file_name.clone()),
+                ("backup".to_string(), 19, 9, file_name.clone()),
+            ],
+            summary
+        );
+        assert!(matches!(
+            diagnostics[0].error,
+            ServerConfigError::ParseError(_)
+        ));
+        assert!(matches!(
+            diagnostics[1].error,
+            ServerConfigError::BadHealthCheckDefinition(_)
+        ));
+        assert!(matches!(
+            &diagnostics[3].error,
+            ServerConfigError::UndefinedTag(tag) if tag == "gpus"
+        ));
+        assert!(matches!(
+            &diagnostics[4].error,
+            ServerConfigError::DuplicateServer(_, first) if first == &format!("{}:5:5", file_name)
+        ));
+        assert!(matches!(
+            diagnostics[5].error,
+            ServerConfigError::BadInterpolation(_, _)
+        ));
+
+        assert!(rendered.starts_with("Found 6 problems in the config:\n\n"));
+        assert!(rendered.contains(&format!(
+            r#"error: Found undefined dependency: gpu
+   --> {}:12:26 (server compute)
+   |
+12 |     depends: ["storage", "gpu", "tag:nas", "tag:gpus"]
+   |                          ^^^^^"#,
+            file_name
+        )));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_formats() {
+        let yaml = r#"
+defaults:
+  interface: "eth1"
+  timeout: 2m
+servers:
+  - name: "compute"
+    mac: "00:11:22:33:44:55"
+    vlan: 20
+    depends: ["storage", "firewall"]
+    check:
+      - type: port
+        ip: "10.0.0.3"
+        port: 22
+  - name: "storage"
+    mac: "00:11:22:33:44:66"
+    transport: udp
+    depends: ["firewall"]
+    check:
+      - type: http
+        url: "http://10.0.0.2/health"
+        status: 200
+        retry: 5s
+  - name: "firewall"
+    wake:
+      type: shell
+      command: "etherwake 00:11:22:33:44:77"
+"#;
+        let toml = r#"
+[defaults]
+interface = "eth1"
+timeout = "2m"
+
+[[servers]]
+name = "compute"
+mac = "00:11:22:33:44:55"
+vlan = 20
+depends = ["storage", "firewall"]
+check = [{ type = "port", ip = "10.0.0.3", port = 22 }]
+
+[[servers]]
+name = "storage"
+mac = "00:11:22:33:44:66"
+transport = "udp"
+depends = ["firewall"]
+
+[[servers.check]]
+type = "http"
+url = "http://10.0.0.2/health"
+status = 200
+retry = "5s"
+
+[[servers]]
+name = "firewall"
+wake = { type = "shell", command = "etherwake 00:11:22:33:44:77" }
+"#;
+        let json = r#"
+{
+  "defaults": { "interface": "eth1", "timeout": "2m" },
+  "servers": [
+    {
+      "name": "compute",
+      "mac": "00:11:22:33:44:55",
+      "vlan": 20,
+      "depends": ["storage", "firewall"],
+      "check": [{ "type": "port", "ip": "10.0.0.3", "port": 22 }]
+    },
+    {
+      "name": "storage",
+      "mac": "00:11:22:33:44:66",
+      "transport": "udp",
+      "depends": ["firewall"],
+      "check": [
+        { "type": "http", "url": "http://10.0.0.2/health", "status": 200, "retry": "5s" }
+      ]
+    },
+    {
+      "name": "firewall",
+      "wake": { "type": "shell", "command": "etherwake 00:11:22:33:44:77" }
+    }
+  ]
+}
+"#;
+        let dir = config_dir(
+            "formats",
+            &[
+                ("rallyup.yaml", yaml),
+                ("rallyup.toml", toml),
+                ("rallyup.json", json),
+                ("rallyup.conf", json),
+            ],
+        );
+        let load = |name: &str, format| {
+            parse_server_dependencies(dir.join(name).to_str().unwrap(), format)
+                .unwrap_or_else(|e| panic!("Failed to load {}: {}", name, e))
+        };
+
+        let summarise = |servers: Vec<Server>| {
+            servers
+                .into_iter()
+                .map(|s| {
+                    let checks: Vec<_> = s
+                        .check
+                        .iter()
+                        .map(|c| (c.to_string(), c.retry, c.timeout))
+                        .collect();
+                    (
+                        s.name,
+                        s.mac.map(|m| m.to_string()),
+                        s.interface,
+                        s.vlan.map(|v| v.tag.id),
+                        s.transport,
+                        checks,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let expected = summarise(load("rallyup.yaml", None));
+        assert_eq!(
+            vec!["firewall", "storage", "compute"],
+            expected.iter().map(|s| s.0.as_str()).collect::<Vec<_>>()
+        );
+        for (name, format) in [
+            ("rallyup.toml", None),
+            ("rallyup.json", None),
+            ("rallyup.conf", Some(config::Format::Json)),
+        ] {
+            assert_eq!(expected, summarise(load(name, format)), "{}", name);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_formats_validation() {
+        // The same invalid health check in every format
+        let dir = config_dir(
+            "formats-validation",
+            &[
+                (
+                    "rallyup.yaml",
+                    "- name: web\n  mac: 00:11:22:33:44:55\n  check:\n    - type: http\n      url: http://10.0.0.1\n",
+                ),
+                (
+                    "rallyup.toml",
+                    "[[servers]]\nname = \"web\"\nmac = \"00:11:22:33:44:55\"\ncheck = [{ type = \"http\", url = \"http://10.0.0.1\" }]\n",
+                ),
+                (
+                    "rallyup.json",
+                    r#"[{ "name": "web", "mac": "00:11:22:33:44:55", "check": [{ "type": "http", "url": "http://10.0.0.1" }] }]"#,
+                ),
+            ],
+        );
+
+        for name in ["rallyup.yaml", "rallyup.toml", "rallyup.json"] {
+            let result = invalid(parse_server_dependencies(
+                dir.join(name).to_str().unwrap(),
+                None,
+            ));
+            assert_eq!(1, result.len(), "{}", name);
+            assert!(
+                matches!(
+                    result[0].error,
+                    ServerConfigError::BadHealthCheckDefinition(_)
+                ),
+                "{}: {:?}",
+                name,
+                result
+            );
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_health_check_success() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/health")
+            .with_status(200)
+            .with_body("healthy")
+            .create_async()
+            .await;
+
+        let url = format!("{}/health", server.url());
+
+        // Status and regex match
+        let status = Some(200);
+        let regex = Some(Regex::new("health").unwrap());
+
+        let result = http_health_check(&url, status, regex).await;
+        assert!(result);
+
+        // Just status
+        let status = Some(200);
+        let regex = None;
+
+        let result = http_health_check(&url, status, regex).await;
+        assert!(result);
+
+        // Just regex
+        let status = None;
+        let regex = Some(Regex::new("health").unwrap());
+
+        let result = http_health_check(&url, status, regex).await;
+        assert!(result);
+    }
+
+    #[tokio::test]
     async fn test_http_health_check_fail() {
         // Mock a failed response
         let mut server = mockito::Server::new_async().await;
@@ -773,6 +2263,49 @@ mod tests {
         assert!(!result);
     }
 
+    #[tokio::test]
+    async fn test_precheck_server() {
+        let yaml_data = r#"
+        - name: "up"
+          mac: "00:11:22:33:44:55"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+            - type: shell
+              command: "echo ready"
+              regex: "ready"
+
+        - name: "down"
+          mac: "66:77:88:99:AA:BB"
+          check:
+            - type: shell
+              command: "true"
+              status: 0
+            - type: shell
+              command: "false"
+              status: 0
+
+        - name: "unknown"
+          mac: "AA:BB:CC:DD:EE:FF"
+        "#;
+
+        let servers: Vec<Server> =
+            serde_yaml_ng::from_str(yaml_data).expect("Failed to parse YAML");
+        let server_state = Arc::new(RwLock::new(servers));
+
+        assert!(precheck_server(server_state.clone(), 0).await);
+        assert!(!precheck_server(server_state.clone(), 1).await);
+        assert!(!precheck_server(server_state.clone(), 2).await);
+
+        let servers = server_state.read().await;
+        assert_eq!(ServerStatus::AlreadyUp, servers[0].status);
+        assert!(matches!(servers[0].check[1].status, CheckStatus::Ok));
+        assert_eq!(ServerStatus::Waiting, servers[1].status);
+        assert!(matches!(servers[1].check[0].status, CheckStatus::Waiting));
+        assert_eq!(ServerStatus::Waiting, servers[2].status);
+    }
+
     #[tokio::test]
     async fn test_health_check_timeout() {
         let mut server = mockito::Server::new_async().await;
@@ -0,0 +1,235 @@
+use serde::Deserialize;
+use std::{io, process::Output, process::Stdio, time::Duration};
+use tokio::process::Command;
+
+use thiserror::Error;
+
+fn default_status() -> i32 {
+    0
+}
+
+fn default_command_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Error)]
+pub enum ShellError {
+    #[error("Invalid shell command: {0}")]
+    InvalidConfig(String),
+
+    #[error("Failed to run shell command: {0}")]
+    SpawnError(#[from] io::Error),
+
+    #[error("Shell command did not finish within {}", humantime::format_duration(*.0))]
+    TimedOut(Duration),
+
+    #[error("Shell command exited with {0}: {1}")]
+    UnexpectedStatus(String, String),
+}
+
+type Result<T> = std::result::Result<T, ShellError>;
+
+/// Power a server on or off with a script, e.g. `etherwake` on a router over SSH or a vendor CLI
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShellConfig {
+    pub command: String,
+    /// Exit code the command returns once the server has been told to power on or off
+    #[serde(default = "default_status")]
+    pub status: i32,
+    #[serde(default = "default_command_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl ShellConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.command.trim().is_empty() {
+            return Err(ShellError::InvalidConfig("`command` is empty".into()));
+        }
+        Ok(())
+    }
+}
+
+/// Shut a server down by running a command on it over SSH.
+///
+/// Runs the `ssh` client in batch mode, so authentication has to work without a password prompt,
+/// e.g. with a key from the agent or `identity`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SshConfig {
+    pub host: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Private key to authenticate with
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default = "default_ssh_command")]
+    pub command: String,
+    #[serde(default = "default_command_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_ssh_command() -> String {
+    "sudo shutdown -h now".to_string()
+}
+
+impl SshConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.host.is_empty() {
+            return Err(ShellError::InvalidConfig("`host` is empty".into()));
+        }
+        if self.command.trim().is_empty() {
+            return Err(ShellError::InvalidConfig("`command` is empty".into()));
+        }
+        Ok(())
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec!["-o".to_string(), "BatchMode=yes".to_string()];
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(identity) = &self.identity {
+            args.extend(["-i".to_string(), identity.clone()]);
+        }
+        args.push(match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        });
+        args.push(self.command.clone());
+        args
+    }
+}
+
+fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Commands that hit the timeout should not keep running in the background
+        .kill_on_drop(true);
+    command
+}
+
+/// Run `command` with `sh -c`, capturing both stdout and stderr
+pub async fn run(command_line: &str, env: &[(String, String)]) -> io::Result<Output> {
+    command("sh")
+        .arg("-c")
+        .arg(command_line)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .output()
+        .await
+}
+
+/// Wait for `output` and check that the command exited with `expected_status`
+async fn check_status(
+    output: impl std::future::Future<Output = io::Result<Output>>,
+    expected_status: i32,
+    timeout: Duration,
+) -> Result<()> {
+    let output = tokio::time::timeout(timeout, output)
+        .await
+        .map_err(|_| ShellError::TimedOut(timeout))??;
+
+    if output.status.code() != Some(expected_status) {
+        let status = match output.status.code() {
+            Some(code) => format!("status {}", code),
+            None => "no status (killed by a signal)".to_string(),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(ShellError::UnexpectedStatus(status, stderr));
+    }
+
+    Ok(())
+}
+
+/// Run the command with the server fields in `env` and check its exit code
+pub async fn run_action(config: &ShellConfig, env: &[(String, String)]) -> Result<()> {
+    check_status(run(&config.command, env), config.status, config.timeout).await
+}
+
+/// Run the shutdown command on the server, `ssh` exits with the status of the remote command
+pub async fn ssh_shutdown(config: &SshConfig) -> Result<()> {
+    let output = command("ssh").args(config.args()).output();
+    check_status(output, 0, config.timeout).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(command: &str) -> ShellConfig {
+        ShellConfig {
+            command: command.to_string(),
+            status: default_status(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_action() {
+        let env = vec![("RALLYUP_MAC".to_string(), "00:11:22:33:44:55".to_string())];
+
+        let result = run_action(
+            &config(r#"test "$RALLYUP_MAC" = "00:11:22:33:44:55""#),
+            &env,
+        )
+        .await;
+        assert!(result.is_ok(), "{:?}", result);
+
+        let mut config = config("exit 3");
+        config.status = 3;
+        let result = run_action(&config, &env).await;
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_run_action_failure() {
+        let result = run_action(&config("echo 'no route to host' >&2; exit 1"), &[]).await;
+        assert!(matches!(
+            result,
+            Err(ShellError::UnexpectedStatus(status, stderr))
+                if status == "status 1" && stderr == "no route to host"
+        ));
+
+        let mut config = config("sleep 5");
+        config.timeout = Duration::from_millis(100);
+        let result = run_action(&config, &[]).await;
+        assert!(matches!(result, Err(ShellError::TimedOut(_))));
+    }
+
+    #[test]
+    fn test_ssh_args() {
+        let mut config: SshConfig =
+            serde_yaml_ng::from_str("host: 10.0.0.5").expect("Failed to parse YAML");
+        assert_eq!(
+            vec!["-o", "BatchMode=yes", "10.0.0.5", "sudo shutdown -h now"],
+            config.args()
+        );
+        assert!(config.validate().is_ok());
+
+        config.user = Some("admin".into());
+        config.port = Some(2222);
+        config.identity = Some("/root/.ssh/shutdown".into());
+        config.command = "poweroff".into();
+        assert_eq!(
+            vec![
+                "-o",
+                "BatchMode=yes",
+                "-p",
+                "2222",
+                "-i",
+                "/root/.ssh/shutdown",
+                "admin@10.0.0.5",
+                "poweroff"
+            ],
+            config.args()
+        );
+
+        config.host = String::new();
+        assert!(matches!(
+            config.validate(),
+            Err(ShellError::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use pnet::util::MacAddr;
+use std::net::IpAddr;
+
+use crate::wol::{self, Destination, SecureOnPassword, Transport, Vlan, WOLError, WolSender};
+
+type Result<T> = std::result::Result<T, WOLError>;
+
+/// Everything needed to deliver a magic packet to a single server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WakeTarget {
+    pub name: String,
+    pub mac: MacAddr,
+    pub destination: Destination,
+    pub transport: Transport,
+    pub interface: Option<String>,
+    pub vlan: Option<Vlan>,
+    pub password: Option<SecureOnPassword>,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+/// Delivers magic packets to servers.
+///
+/// The orchestrator only talks to this trait so that the wake sequence can run against
+/// [`RecordingTransport`] in tests, without a network interface or elevated privileges.
+pub trait WakeTransport: Send {
+    fn send(&mut self, target: &WakeTarget) -> Result<()>;
+
+    /// Packets are not actually transmitted, so there is no point waiting for servers to come up
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+impl WakeTransport for WolSender {
+    fn send(&mut self, target: &WakeTarget) -> Result<()> {
+        let interface = target.interface.as_deref().ok_or_else(|| {
+            WOLError::InterfaceNotFound(format!("no interface selected for {}", target.name))
+        })?;
+        self.send_packet(
+            target.mac,
+            target.destination,
+            interface,
+            target.vlan.as_ref(),
+            target.password.as_ref(),
+        )
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.is_pcap_only()
+    }
+}
+
+/// Sends the magic packet as a UDP datagram
+#[derive(Debug, Default)]
+pub struct UdpTransport;
+
+impl WakeTransport for UdpTransport {
+    fn send(&mut self, target: &WakeTarget) -> Result<()> {
+        wol::send_wol_udp(
+            target.mac,
+            target.address,
+            target.port,
+            target.interface.as_deref(),
+            target.password.as_ref(),
+        )
+    }
+}
+
+/// Routes every target to the transport configured for it
+#[derive(Default)]
+pub struct SystemTransport {
+    pub ethernet: WolSender,
+    pub udp: UdpTransport,
+}
+
+impl WakeTransport for SystemTransport {
+    fn send(&mut self, target: &WakeTarget) -> Result<()> {
+        match target.transport {
+            Transport::Ethernet => self.ethernet.send(target),
+            // UDP datagrams are not recorded to the pcap file, but should not go out during a dry run
+            Transport::Udp if self.is_dry_run() => Ok(()),
+            Transport::Udp => self.udp.send(target),
+        }
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.ethernet.is_dry_run()
+    }
+}
+
+/// Keeps every target in memory instead of sending anything
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingTransport {
+    pub sent: Vec<WakeTarget>,
+}
+
+#[cfg(test)]
+impl WakeTransport for RecordingTransport {
+    fn send(&mut self, target: &WakeTarget) -> Result<()> {
+        self.sent.push(target.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    fn target(transport: Transport) -> WakeTarget {
+        WakeTarget {
+            name: "server".into(),
+            mac: MacAddr::new(0x01, 0x23, 0x45, 0x67, 0x89, 0xAB),
+            destination: Destination::Broadcast,
+            transport,
+            interface: None,
+            vlan: None,
+            password: None,
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 9,
+        }
+    }
+
+    #[test]
+    fn test_recording_transport() {
+        let mut transport = RecordingTransport::default();
+        let target = target(Transport::Ethernet);
+
+        transport.send(&target).unwrap();
+        transport.send(&target).unwrap();
+
+        assert_eq!(vec![target.clone(), target], transport.sent);
+        assert!(!transport.is_dry_run());
+    }
+
+    #[test]
+    fn test_ethernet_without_interface() {
+        let mut transport = SystemTransport::default();
+        let result = transport.send(&target(Transport::Ethernet));
+        assert!(
+            matches!(result, Err(WOLError::InterfaceNotFound(_))),
+            "Expected InterfaceNotFound error."
+        );
+    }
+
+    #[test]
+    fn test_system_transport_udp() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+
+        let mut target = target(Transport::Udp);
+        target.port = receiver.local_addr().unwrap().port();
+
+        let mut transport = SystemTransport::default();
+        transport.send(&target).expect("failed to send UDP packet");
+
+        let mut buffer = [0u8; 512];
+        let size = receiver.recv(&mut buffer).expect("no UDP packet received");
+        assert_eq!(102, size);
+    }
+}
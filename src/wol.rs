@@ -1,19 +1,65 @@
-use pnet::datalink::NetworkInterface;
 use pnet::datalink::{self, Channel::Ethernet};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::datalink::{DataLinkSender, NetworkInterface};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
 use pnet::packet::{MutablePacket, Packet};
 use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::str::FromStr;
+use std::time::SystemTime;
 
 use thiserror::Error;
 
+use crate::interfaces;
+use crate::pcap::PcapWriter;
+
 const SIZE_DST_MAC: usize = 6;
 const SIZE_SRC_MAC: usize = 6;
 const SIZE_ETHERTYPE: usize = 2;
 const SIZE_VLAN_ETHERTYPE: usize = 2;
 const SIZE_VLAN_TAG: usize = 2;
 const SIZE_WOL_PAYLOAD: usize = 102;
+const SIZE_SECUREON_PASSWORD: usize = 6;
 
 const WOL_ETHERTYPE: [u8; 2] = [0x08, 0x42];
+const VLAN_ETHERTYPE: [u8; 2] = [0x81, 0x00];
+const SERVICE_VLAN_ETHERTYPE: [u8; 2] = [0x88, 0xA8];
+
+const MAX_VLAN_ID: u16 = 0x0FFF;
+const MAX_VLAN_PRIORITY: u8 = 7;
+
+/// How the magic packet is delivered to the target machine
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Raw Ethernet frame with EtherType 0x0842, requires root or CAP_NET_RAW
+    #[default]
+    Ethernet,
+    /// Magic packet payload sent as a UDP datagram, can be sent unprivileged
+    Udp,
+}
+
+/// Ethernet destination of raw WOL frames
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Destination {
+    /// Broadcast to every machine in the segment
+    #[default]
+    Broadcast,
+    /// Addressed to the target MAC, for switches that rate-limit or drop broadcast on trunk ports
+    Unicast,
+}
+
+impl Destination {
+    fn address(self, target: MacAddr) -> MacAddr {
+        match self {
+            Destination::Broadcast => MacAddr::broadcast(),
+            Destination::Unicast => target,
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum WOLError {
@@ -28,10 +74,254 @@ pub enum WOLError {
 
     #[error("Failed to send WOL packet for server: {0}")]
     WOLPacketError(String),
+
+    #[error("Invalid SecureOn password: {0}")]
+    InvalidPassword(String),
+
+    #[error("Invalid VLAN tag: {0}")]
+    InvalidVlan(String),
+
+    #[error("Failed to write pcap file: {0}")]
+    PcapError(std::io::Error),
 }
 
 type Result<T> = std::result::Result<T, WOLError>;
 
+/// SecureOn password appended to the magic packet.
+///
+/// Accepts 4 or 6 bytes, written either as bare hex (`a1b2c3d4`) or in MAC-style notation
+/// (`a1:b2:c3:d4:e5:f6` or `a1-b2-c3-d4-e5-f6`).
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct SecureOnPassword(Vec<u8>);
+
+impl SecureOnPassword {
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for SecureOnPassword {
+    type Err = WOLError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || WOLError::InvalidPassword(s.to_string());
+
+        // Only split on the first separator, so that mixing `:` and `-` is rejected
+        let digits: String = if let Some(separator) = s.chars().find(|c| matches!(c, ':' | '-')) {
+            let groups: Vec<&str> = s.split(separator).collect();
+            if groups.iter().any(|group| group.len() != 2) {
+                return Err(invalid());
+            }
+            groups.concat()
+        } else {
+            s.to_string()
+        };
+
+        if !matches!(digits.len(), 8 | 12) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid()))
+            .collect::<Result<Vec<u8>>>()?;
+
+        Ok(SecureOnPassword(bytes))
+    }
+}
+
+impl TryFrom<String> for SecureOnPassword {
+    type Error = WOLError;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+/// MAC address of a machine to wake up.
+///
+/// Accepts colon or dash separated (`00:11:22:33:44:55`, `00-11-22-33-44-55`), Cisco dotted
+/// (`0011.2233.4455`) and bare hex (`001122334455`) notation, in either case.
+/// Broadcast and multicast addresses are rejected, since they can not belong to a single NIC.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress(MacAddr);
+
+impl MacAddress {
+    pub fn addr(&self) -> MacAddr {
+        self.0
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = WOLError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| WOLError::InvalidMAC(format!("{} ({})", s, reason));
+
+        // Only split on the first separator, so that mixing `:` and `-` is rejected
+        let digits: String = if let Some(separator) = s.chars().find(|c| matches!(c, ':' | '-')) {
+            let groups: Vec<&str> = s.split(separator).collect();
+            if groups.len() != 6 || groups.iter().any(|group| group.len() != 2) {
+                return Err(invalid("expected 6 groups of 2 hex digits"));
+            }
+            groups.concat()
+        } else if s.contains('.') {
+            let groups: Vec<&str> = s.split('.').collect();
+            if groups.len() != 3 || groups.iter().any(|group| group.len() != 4) {
+                return Err(invalid("expected 3 groups of 4 hex digits"));
+            }
+            groups.concat()
+        } else {
+            s.to_string()
+        };
+
+        if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid("expected 12 hex digits"));
+        }
+
+        let mut octets = [0u8; 6];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            *octet = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16)
+                .map_err(|_| invalid("expected 12 hex digits"))?;
+        }
+        let mac = MacAddr::from(octets);
+
+        if mac.is_broadcast() {
+            return Err(invalid("broadcast address"));
+        }
+        if mac.is_multicast() {
+            return Err(invalid("multicast address"));
+        }
+
+        Ok(MacAddress(mac))
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = WOLError;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac: MacAddress) -> Self {
+        mac.to_string()
+    }
+}
+
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A single 802.1Q tag control information (TCI) field
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VlanTag {
+    pub id: u16,
+    /// Priority code point (PCP), 0-7
+    #[serde(default)]
+    pub priority: u8,
+    /// Drop eligible indicator (DEI)
+    #[serde(default)]
+    pub dei: bool,
+}
+
+impl VlanTag {
+    fn validate(&self) -> Result<()> {
+        if self.id > MAX_VLAN_ID {
+            return Err(WOLError::InvalidVlan(format!(
+                "VLAN ID {} is larger than {}",
+                self.id, MAX_VLAN_ID
+            )));
+        }
+        if self.priority > MAX_VLAN_PRIORITY {
+            return Err(WOLError::InvalidVlan(format!(
+                "priority {} is larger than {}",
+                self.priority, MAX_VLAN_PRIORITY
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// VLAN tagging for the WOL frame.
+///
+/// `tag` is the customer tag (C-tag, EtherType 0x8100). When `outer` is set, the frame is
+/// double tagged (802.1ad QinQ) with `outer` as the service tag (S-tag, EtherType 0x88A8).
+///
+/// Can be configured either as a bare VLAN ID (`vlan: 100`) or as a map:
+/// ```yaml
+/// vlan:
+///   id: 100
+///   priority: 5
+///   dei: false
+///   outer:
+///     id: 20
+/// ```
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "VlanConfig")]
+pub struct Vlan {
+    pub tag: VlanTag,
+    pub outer: Option<VlanTag>,
+}
+
+impl From<u16> for Vlan {
+    fn from(id: u16) -> Self {
+        Vlan {
+            tag: VlanTag {
+                id,
+                ..Default::default()
+            },
+            outer: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VlanConfig {
+    Id(u16),
+    Tagged {
+        id: u16,
+        #[serde(default)]
+        priority: u8,
+        #[serde(default)]
+        dei: bool,
+        #[serde(default)]
+        outer: Option<VlanTag>,
+    },
+}
+
+impl TryFrom<VlanConfig> for Vlan {
+    type Error = WOLError;
+
+    fn try_from(config: VlanConfig) -> Result<Self> {
+        let vlan = match config {
+            VlanConfig::Id(id) => Vlan::from(id),
+            VlanConfig::Tagged {
+                id,
+                priority,
+                dei,
+                outer,
+            } => Vlan {
+                tag: VlanTag { id, priority, dei },
+                outer,
+            },
+        };
+
+        vlan.tag.validate()?;
+        if let Some(outer) = &vlan.outer {
+            outer.validate()?;
+        }
+        Ok(vlan)
+    }
+}
+
 // Ethernet Frame Layout:
 // -----------------------------------------------------------------------------
 // | Destination MAC  | Source MAC      | VLAN EtherType | VLAN Tag | WOL EtherType | WOL Magic Packet                              |
@@ -39,50 +329,69 @@ type Result<T> = std::result::Result<T, WOLError>;
 // -----------------------------------------------------------------------------
 // | 6 bytes          | 6 bytes         | 2 bytes        | 2 bytes  | 2 bytes       | 102 bytes                                     |
 // -----------------------------------------------------------------------------
+// With 802.1ad double tagging, an S-tag is inserted in front of the VLAN EtherType:
+// | Destination MAC | Source MAC | S-tag EtherType (0x88A8) | S-tag | VLAN EtherType (0x8100) | VLAN Tag | WOL EtherType | ... |
 // Detailed Breakdown of Each Component:
-// - Destination MAC (6 bytes): The destination MAC address, usually the broadcast MAC (FF:FF:FF:FF:FF:FF) for WOL packets.
+// - Destination MAC (6 bytes): The destination MAC address, the broadcast MAC (FF:FF:FF:FF:FF:FF) by default,
+//   or the target MAC itself when the server is configured with `destination: unicast`.
 // - Source MAC (6 bytes): The source MAC address, which is the MAC address of the sending interface.
+// - S-tag EtherType (2 bytes, QinQ only): 0x88A8 to indicate the presence of an outer service tag.
+// - S-tag (2 bytes, QinQ only): Same layout as the VLAN tag, for the outer (provider) VLAN.
 // - VLAN EtherType (2 bytes): The EtherType field for VLAN tagging, which is always 0x8100 to indicate the presence of a VLAN tag.
-// - VLAN Tag (2 bytes): The VLAN tag, which contains 12 bits for the VLAN ID and 4 bits for priority and CFI (Canonical Format Indicator).
+// - VLAN Tag (2 bytes): The VLAN tag, 3 bits of priority (PCP), 1 bit drop eligible indicator (DEI) and 12 bits for the VLAN ID.
 // - WOL EtherType (2 bytes): The EtherType field indicating a Wake-on-LAN packet, which is 0x0842.
 // - WOL Magic Packet (102 bytes): The WOL magic packet, consisting of 6 bytes of FF followed by the target MAC address repeated 16 times.
+//   If a SecureOn password is configured, its 4 or 6 bytes are appended after the last MAC repetition.
 
-fn create_wol_payload(mac: MacAddr) -> Vec<u8> {
+fn create_wol_payload(mac: MacAddr, password: Option<&SecureOnPassword>) -> Vec<u8> {
     // 6 bytes of FF followed by target MAC address repeated 16 times
-    let mut packet = vec![0xFF; 6];
+    let mut packet = Vec::with_capacity(SIZE_WOL_PAYLOAD + SIZE_SECUREON_PASSWORD);
+    packet.extend_from_slice(&[0xFF; 6]);
     for _ in 0..16 {
         packet.extend_from_slice(&mac.octets());
     }
+    if let Some(password) = password {
+        packet.extend_from_slice(password.bytes());
+    }
     packet
 }
 
-fn vlan_to_bytes(vlan: u16) -> Vec<u8> {
-    // Do not need priority bits, using only the remaining 14 bits of the tag
-    let vlan_tag = (vlan & 0x0FFF).to_be_bytes();
-    vlan_tag.to_vec()
+fn vlan_to_bytes(tag: &VlanTag) -> [u8; SIZE_VLAN_TAG] {
+    let tci = (u16::from(tag.priority & MAX_VLAN_PRIORITY) << 13)
+        | (u16::from(tag.dei) << 12)
+        | (tag.id & MAX_VLAN_ID);
+    tci.to_be_bytes()
+}
+
+/// VLAN headers from the outermost to the innermost, each tag paired with the EtherType that announces it
+fn vlan_headers(vlan: Option<&Vlan>) -> Vec<([u8; SIZE_VLAN_ETHERTYPE], &VlanTag)> {
+    match vlan {
+        None => vec![],
+        Some(Vlan { tag, outer: None }) => vec![(VLAN_ETHERTYPE, tag)],
+        Some(Vlan {
+            tag,
+            outer: Some(outer),
+        }) => vec![(SERVICE_VLAN_ETHERTYPE, outer), (VLAN_ETHERTYPE, tag)],
+    }
+}
+
+fn find_interface(interface_name: &str) -> Result<NetworkInterface> {
+    interfaces::find_interface(interface_name)
+        .ok_or_else(|| WOLError::InterfaceNotFound(interface_name.to_string()))
 }
 
 pub fn build_wol_packet(
-    maybe_mac: &str,
-    interface_name: &str,
-    vlan_id: Option<u16>,
-) -> Result<(Vec<u8>, NetworkInterface)> {
-    let mac = maybe_mac
-        .parse::<MacAddr>()
-        .map_err(|_| WOLError::InvalidMAC(maybe_mac.to_string()))?;
-
-    let wol_packet = create_wol_payload(mac);
-
-    let interface = datalink::interfaces()
-        .into_iter()
-        .find(|iface| iface.name == interface_name)
-        .ok_or_else(|| WOLError::InterfaceNotFound(interface_name.to_string()))?;
-
-    let payload_size = if vlan_id.is_some() {
-        SIZE_VLAN_TAG + SIZE_VLAN_ETHERTYPE
-    } else {
-        0
-    } + SIZE_WOL_PAYLOAD;
+    mac: MacAddr,
+    destination: Destination,
+    interface: &NetworkInterface,
+    vlan: Option<&Vlan>,
+    password: Option<&SecureOnPassword>,
+) -> Result<Vec<u8>> {
+    let wol_packet = create_wol_payload(mac, password);
+
+    let headers = vlan_headers(vlan);
+
+    let payload_size = headers.len() * (SIZE_VLAN_TAG + SIZE_VLAN_ETHERTYPE) + wol_packet.len();
 
     let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
     let mut buffer = vec![0u8; packet_size];
@@ -90,58 +399,227 @@ pub fn build_wol_packet(
     let mut packet = MutableEthernetPacket::new(&mut buffer[..])
         .ok_or_else(|| WOLError::WOLPacketError("failed to create ethernet packet".into()))?;
 
-    packet.set_destination(MacAddr::broadcast());
+    packet.set_destination(destination.address(mac));
 
     if let Some(mac) = interface.mac {
         packet.set_source(mac);
     } else {
-        return Err(WOLError::NetworkError(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        return Err(WOLError::NetworkError(std::io::Error::other(
             "failed to get source MAC address of the interface",
         )));
     }
 
-    let payload_offset = if let Some(vlan) = vlan_id {
-        packet.set_ethertype(EtherTypes::Vlan);
+    // Each tag is followed by the EtherType of the next header: either the next (inner) tag or the WOL payload
+    let mut payload_offset = 0;
+    if let Some((ethertype, _)) = headers.first() {
+        packet.set_ethertype(EtherType(u16::from_be_bytes(*ethertype)));
 
-        let vlan_tag = vlan_to_bytes(vlan);
-        packet.payload_mut()[..SIZE_VLAN_TAG].copy_from_slice(&vlan_tag);
+        let next_ethertypes = headers
+            .iter()
+            .skip(1)
+            .map(|(ethertype, _)| *ethertype)
+            .chain(std::iter::once(WOL_ETHERTYPE));
 
-        // Set WOL Ethertype manually
-        packet.payload_mut()[SIZE_VLAN_TAG..(SIZE_VLAN_TAG + SIZE_ETHERTYPE)]
-            .copy_from_slice(&WOL_ETHERTYPE);
+        for ((_, tag), next_ethertype) in headers.iter().zip(next_ethertypes) {
+            packet.payload_mut()[payload_offset..(payload_offset + SIZE_VLAN_TAG)]
+                .copy_from_slice(&vlan_to_bytes(tag));
+            payload_offset += SIZE_VLAN_TAG;
 
-        SIZE_VLAN_TAG + SIZE_ETHERTYPE
+            // Set the next Ethertype manually
+            packet.payload_mut()[payload_offset..(payload_offset + SIZE_VLAN_ETHERTYPE)]
+                .copy_from_slice(&next_ethertype);
+            payload_offset += SIZE_VLAN_ETHERTYPE;
+        }
     } else {
         packet.set_ethertype(EtherTypes::WakeOnLan);
-        0
-    };
+    }
 
     packet.payload_mut()[payload_offset..].copy_from_slice(&wol_packet);
 
-    Ok((buffer, interface))
+    Ok(buffer)
 }
 
-pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u16>) -> Result<()> {
-    let (packet_buffer, interface) = build_wol_packet(maybe_mac, interface_name, vlan_id)?;
+/// Sends WOL frames over raw datalink channels.
+///
+/// Looking up an interface and opening a datalink channel on it is relatively expensive, so both
+/// are cached per interface name and reused for every packet sent while the sender is alive.
+/// The sender is meant to be kept around for the whole run rather than created per packet.
+#[derive(Default)]
+pub struct WolSender {
+    interfaces: HashMap<String, NetworkInterface>,
+    channels: HashMap<String, Box<dyn DataLinkSender>>,
+    pcap: Option<PcapWriter<File>>,
+    pcap_only: bool,
+}
+
+impl WolSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record every frame sent to `pcap`.
+    /// With `pcap_only`, frames are recorded but never transmitted.
+    pub fn with_pcap(mut self, pcap: PcapWriter<File>, pcap_only: bool) -> Self {
+        self.pcap = Some(pcap);
+        self.pcap_only = pcap_only;
+        self
+    }
+
+    /// Use `interface` instead of looking it up on this machine
+    #[cfg(test)]
+    pub fn with_interface(mut self, interface: NetworkInterface) -> Self {
+        self.interfaces.insert(interface.name.clone(), interface);
+        self
+    }
 
-    let packet = EthernetPacket::new(&packet_buffer)
-        .expect("`packet_buffer` was created by a `MutableEthernetPacket`, should not error here");
+    pub fn is_pcap_only(&self) -> bool {
+        self.pcap_only
+    }
+
+    pub fn send_packet(
+        &mut self,
+        mac: MacAddr,
+        destination: Destination,
+        interface_name: &str,
+        vlan: Option<&Vlan>,
+        password: Option<&SecureOnPassword>,
+    ) -> Result<()> {
+        let interface = self.interface(interface_name)?.clone();
+        let packet_buffer = build_wol_packet(mac, destination, &interface, vlan, password)?;
+
+        let packet = EthernetPacket::new(&packet_buffer).expect(
+            "`packet_buffer` was created by a `MutableEthernetPacket`, should not error here",
+        );
+
+        if !self.pcap_only && self.transmit(&interface, packet.packet()).is_err() {
+            // The cached channel may have gone bad, e.g. the interface was reset and came back
+            // with a new index. Forget everything we know about the interface and retry once
+            // with a freshly opened channel before giving up.
+            self.invalidate(interface_name);
+            let interface = self.interface(interface_name)?.clone();
+            self.transmit(&interface, packet.packet())?;
+        }
+
+        if let Some(pcap) = &mut self.pcap {
+            pcap.write_frame(packet.packet(), SystemTime::now())
+                .map_err(WOLError::PcapError)?;
+        }
 
-    let mut tx = match datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(tx, _)) => tx,
-        Ok(_) => {
-            return Err(WOLError::NetworkError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "unhandled channel type for this interface",
+        Ok(())
+    }
+
+    /// Drop the cached interface and channel, they will be reopened on the next send
+    pub fn invalidate(&mut self, interface_name: &str) {
+        self.channels.remove(interface_name);
+        self.interfaces.remove(interface_name);
+    }
+
+    /// Send a frame over the channel of `interface`, opening the channel if needed
+    fn transmit(&mut self, interface: &NetworkInterface, frame: &[u8]) -> Result<()> {
+        let tx = self.channel(interface)?;
+        tx.send_to(frame, None)
+            .ok_or_else(|| std::io::Error::other("failed to send WOL packet"))??;
+        Ok(())
+    }
+
+    fn interface(&mut self, interface_name: &str) -> Result<&NetworkInterface> {
+        if !self.interfaces.contains_key(interface_name) {
+            let interface = find_interface(interface_name)?;
+            self.interfaces
+                .insert(interface_name.to_string(), interface);
+        }
+        Ok(&self.interfaces[interface_name])
+    }
+
+    fn channel(&mut self, interface: &NetworkInterface) -> Result<&mut Box<dyn DataLinkSender>> {
+        if !self.channels.contains_key(&interface.name) {
+            let tx = match datalink::channel(interface, Default::default()) {
+                Ok(Ethernet(tx, _)) => tx,
+                Ok(_) => {
+                    return Err(WOLError::NetworkError(std::io::Error::other(
+                        "unhandled channel type for this interface",
+                    )))
+                }
+                Err(e) => return Err(WOLError::NetworkError(e)),
+            };
+            self.channels.insert(interface.name.clone(), tx);
+        }
+        Ok(self
+            .channels
+            .get_mut(&interface.name)
+            .expect("channel was inserted above"))
+    }
+}
+
+/// Link-local (and interface-local) IPv6 destinations such as the all-nodes multicast address
+/// `ff02::1` are ambiguous without the interface they should be sent out of
+pub fn requires_interface(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(address) => {
+            let first_segment = address.segments()[0];
+            let link_local_unicast = first_segment & 0xFFC0 == 0xFE80;
+            // ffXY::, where Y is the multicast scope: 1 for interface-local, 2 for link-local
+            let link_local_multicast =
+                address.is_multicast() && matches!(first_segment & 0x000F, 0x1 | 0x2);
+            link_local_unicast || link_local_multicast
+        }
+    }
+}
+
+fn udp_destination(address: IpAddr, port: u16, interface_name: Option<&str>) -> Result<SocketAddr> {
+    match address {
+        IpAddr::V6(v6) if requires_interface(address) => {
+            let interface_name = interface_name.ok_or_else(|| {
+                WOLError::InterfaceNotFound(format!("{} requires an interface", address))
+            })?;
+            let interface = find_interface(interface_name)?;
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                v6,
+                port,
+                0,
+                interface.index,
             )))
         }
-        Err(e) => return Err(WOLError::NetworkError(e)),
+        _ => Ok(SocketAddr::new(address, port)),
+    }
+}
+
+/// Send the magic packet as a UDP datagram to `address`.
+///
+/// `interface_name` is only used to scope link-local IPv6 destinations, e.g. `ff02::1`,
+/// see [`requires_interface`].
+pub fn send_wol_udp(
+    mac: MacAddr,
+    address: IpAddr,
+    port: u16,
+    interface_name: Option<&str>,
+    password: Option<&SecureOnPassword>,
+) -> Result<()> {
+    let payload = create_wol_payload(mac, password);
+    let destination = udp_destination(address, port, interface_name)?;
+
+    let socket = match address {
+        IpAddr::V4(_) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            // Needed for both the limited broadcast (255.255.255.255) and directed subnet broadcasts,
+            // harmless for unicast destinations
+            socket.set_broadcast(true)?;
+            socket
+        }
+        // IPv6 has no broadcast, multicast is handled through the scope of the destination
+        IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
     };
 
-    tx.send_to(packet.packet(), None).ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::Other, "failed to send WOL packet")
-    })??;
+    let sent = socket.send_to(&payload, destination)?;
+    if sent != payload.len() {
+        return Err(WOLError::WOLPacketError(format!(
+            "only sent {} of {} bytes to {}",
+            sent,
+            payload.len(),
+            address
+        )));
+    }
 
     Ok(())
 }
@@ -149,25 +627,240 @@ pub fn send_wol_packet(maybe_mac: &str, interface_name: &str, vlan_id: Option<u1
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::interfaces::test_interface;
+
+    #[test]
+    fn test_mac_address_formats() {
+        let expected = MacAddr::new(0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E);
+        for mac in [
+            "00:1a:2b:3c:4d:5e",
+            "00:1A:2B:3C:4D:5E",
+            "00-1a-2b-3c-4d-5e",
+            "001a.2b3c.4d5e",
+            "001A2B3C4D5E",
+        ] {
+            let parsed = mac.parse::<MacAddress>().unwrap();
+            assert_eq!(expected, parsed.addr(), "{}", mac);
+            assert_eq!("00:1a:2b:3c:4d:5e", parsed.to_string());
+        }
+    }
 
     #[test]
     fn test_invalid_mac_address() {
-        let invalid_mac = "random MAC";
-        let interface_name = "eth0";
+        for mac in [
+            "random MAC",
+            "00:11:22:33:44",
+            "00:11:22:33:44:55:66",
+            "0:11:22:33:44:55",
+            "00:11:22:33:44:GG",
+            "0011.2233.44",
+            "00112233445",
+            // Mixed separators
+            "00:11-22:33-44:55",
+            "00-11-22-33-44:55",
+            // Broadcast
+            "ff:ff:ff:ff:ff:ff",
+            // Multicast, e.g. IPv4 multicast and spanning tree
+            "01:00:5e:00:00:01",
+            "0180.c200.0000",
+        ] {
+            assert!(
+                matches!(mac.parse::<MacAddress>(), Err(WOLError::InvalidMAC(_))),
+                "Expected {} to be rejected",
+                mac
+            );
+        }
+    }
+
+    #[test]
+    fn test_missing_network_interface() {
+        let mac = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let non_existent_interface = "nonexistent_iface";
 
-        let result = send_wol_packet(invalid_mac, interface_name, None);
+        let result = WolSender::new().send_packet(
+            mac,
+            Destination::Broadcast,
+            non_existent_interface,
+            None,
+            None,
+        );
         assert!(
-            matches!(result, Err(WOLError::InvalidMAC(_))),
-            "Expected InvalidMAC error."
+            matches!(result, Err(WOLError::InterfaceNotFound(_))),
+            "Expected InterfaceNotFound error."
         );
     }
 
     #[test]
-    fn test_missing_network_interface() {
-        let mac = "00:11:22:33:44:55";
-        let non_existent_interface = "nonexistent_iface";
+    fn test_interface_cache() {
+        let interface = test_interface();
+
+        let mut sender = WolSender::new().with_interface(interface.clone());
+        assert_eq!(sender.interface(&interface.name).unwrap(), &interface);
+
+        sender.invalidate(&interface.name);
+        assert!(!sender.interfaces.contains_key(&interface.name));
+
+        // Failed lookups are not cached
+        assert!(matches!(
+            sender.interface(&interface.name),
+            Err(WOLError::InterfaceNotFound(_))
+        ));
+        assert!(!sender.interfaces.contains_key(&interface.name));
+    }
 
-        let result = send_wol_packet(mac, non_existent_interface, None);
+    #[test]
+    fn test_build_errors_are_not_retried() {
+        let interface = NetworkInterface {
+            mac: None,
+            ..test_interface()
+        };
+        let mac = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+        let mut sender = WolSender::new().with_interface(interface.clone());
+        let result = sender.send_packet(mac, Destination::Broadcast, &interface.name, None, None);
+        assert!(
+            matches!(result, Err(WOLError::NetworkError(_))),
+            "Expected the missing source MAC to be reported, got {:?}",
+            result
+        );
+        // Retrying would have dropped the interface and failed to look it up again
+        assert!(sender.interfaces.contains_key(&interface.name));
+    }
+
+    #[test]
+    fn test_pcap_only() {
+        let maybe_mac = "01:23:45:67:89:AB";
+        let mac = maybe_mac.parse::<MacAddr>().unwrap();
+        let vlan = Vlan::from(0x0101);
+
+        let interface = test_interface();
+
+        let path = std::env::temp_dir().join(format!("rallyup-test-{}.pcap", std::process::id()));
+        let pcap = PcapWriter::create(&path).expect("failed to create pcap file");
+
+        let mut sender = WolSender::new()
+            .with_interface(interface.clone())
+            .with_pcap(pcap, true);
+        sender
+            .send_packet(mac, Destination::Broadcast, &interface.name, None, None)
+            .expect("failed to record packet");
+        sender
+            .send_packet(
+                mac,
+                Destination::Broadcast,
+                &interface.name,
+                Some(&vlan),
+                None,
+            )
+            .expect("failed to record packet");
+
+        // Nothing should have been transmitted
+        assert!(sender.channels.is_empty());
+        drop(sender);
+
+        let buffer = std::fs::read(&path).expect("failed to read pcap file");
+        std::fs::remove_file(&path).unwrap();
+
+        let untagged =
+            build_wol_packet(mac, Destination::Broadcast, &interface, None, None).unwrap();
+        let tagged =
+            build_wol_packet(mac, Destination::Broadcast, &interface, Some(&vlan), None).unwrap();
+
+        // Global header, then a 16 byte record header in front of each frame
+        let first = 24 + 16;
+        let second = first + untagged.len() + 16;
+        assert_eq!(second + tagged.len(), buffer.len());
+        assert_eq!(untagged, buffer[first..first + untagged.len()]);
+        assert_eq!(tagged, buffer[second..]);
+    }
+
+    #[test]
+    fn test_udp_packet() {
+        let maybe_mac = "01:23:45:67:89:AB";
+        let mac = maybe_mac.parse::<MacAddr>().unwrap();
+
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let port = receiver.local_addr().unwrap().port();
+
+        send_wol_udp(mac, IpAddr::V4(Ipv4Addr::LOCALHOST), port, None, None)
+            .expect("failed to send UDP packet");
+
+        let mut buffer = [0u8; 512];
+        let size = receiver.recv(&mut buffer).expect("no UDP packet received");
+
+        assert_eq!(SIZE_WOL_PAYLOAD, size);
+        // WOL packet prefix
+        assert_eq!(vec![0xFF; 6], buffer[..6]);
+        // WOL target MAC x 16
+        assert_eq!(mac.octets().repeat(16), buffer[6..size]);
+    }
+
+    #[test]
+    fn test_udp_ipv6_packet() {
+        let maybe_mac = "01:23:45:67:89:AB";
+        let mac = maybe_mac.parse::<MacAddr>().unwrap();
+
+        let receiver = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let port = receiver.local_addr().unwrap().port();
+
+        send_wol_udp(mac, IpAddr::V6(Ipv6Addr::LOCALHOST), port, None, None)
+            .expect("failed to send UDP packet");
+
+        let mut buffer = [0u8; 512];
+        let size = receiver.recv(&mut buffer).expect("no UDP packet received");
+
+        assert_eq!(SIZE_WOL_PAYLOAD, size);
+        assert_eq!(mac.octets().repeat(16), buffer[6..size]);
+    }
+
+    #[test]
+    fn test_requires_interface() {
+        for address in ["ff02::1", "ff12::1", "ff01::1", "fe80::1"] {
+            assert!(
+                requires_interface(address.parse().unwrap()),
+                "{} should require an interface",
+                address
+            );
+        }
+        for address in [
+            "::1",
+            "fd00::1",
+            "ff05::1",
+            "2001:db8::1",
+            "255.255.255.255",
+        ] {
+            assert!(
+                !requires_interface(address.parse().unwrap()),
+                "{} should not require an interface",
+                address
+            );
+        }
+
+        let result = send_wol_udp(
+            MacAddr::new(0x01, 0x23, 0x45, 0x67, 0x89, 0xAB),
+            "ff02::1".parse().unwrap(),
+            9,
+            None,
+            None,
+        );
+        assert!(
+            matches!(result, Err(WOLError::InterfaceNotFound(_))),
+            "Expected InterfaceNotFound error."
+        );
+
+        let result = send_wol_udp(
+            MacAddr::new(0x01, 0x23, 0x45, 0x67, 0x89, 0xAB),
+            "ff02::1".parse().unwrap(),
+            9,
+            Some("nonexistent_iface"),
+            None,
+        );
         assert!(
             matches!(result, Err(WOLError::InterfaceNotFound(_))),
             "Expected InterfaceNotFound error."
@@ -181,15 +874,12 @@ mod tests {
         let dest_mac = MacAddr::broadcast();
         //let vlan_id = Some(100);
 
-        let interface = datalink::interfaces()
-            .into_iter()
-            .find(|iface| iface.mac.is_some())
-            .expect("cannot find an interface with a MAC address for testing");
+        let interface = test_interface();
 
         let payload_size = SIZE_WOL_PAYLOAD;
         let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
 
-        let (buffer, _interface) = build_wol_packet(maybe_mac, &interface.name, None)
+        let buffer = build_wol_packet(mac, Destination::Broadcast, &interface, None, None)
             .expect("failed to build test packet");
 
         assert_eq!(packet_size, buffer.len());
@@ -206,22 +896,57 @@ mod tests {
         assert_eq!(mac.octets().repeat(16), buffer[20..]);
     }
 
+    #[test]
+    fn test_unicast_ethernet_packet() {
+        let mac = "01:23:45:67:89:AB".parse::<MacAddr>().unwrap();
+        let vlan = Vlan::from(0x0101);
+        let password = "a1:b2:c3:d4".parse::<SecureOnPassword>().unwrap();
+
+        let interface = test_interface();
+
+        let broadcast = build_wol_packet(
+            mac,
+            Destination::Broadcast,
+            &interface,
+            Some(&vlan),
+            Some(&password),
+        )
+        .expect("failed to build test packet");
+        let unicast = build_wol_packet(
+            mac,
+            Destination::Unicast,
+            &interface,
+            Some(&vlan),
+            Some(&password),
+        )
+        .expect("failed to build test packet");
+
+        assert_eq!(broadcast.len(), unicast.len());
+        // Addressed to the target itself
+        assert_eq!(mac.octets(), unicast[..6]);
+        // Source MAC
+        assert_eq!(interface.mac.unwrap().octets(), unicast[6..12]);
+        // VLAN tag, EtherType and magic payload are unchanged
+        assert_eq!(broadcast[6..], unicast[6..]);
+        assert_eq!(vec![0x08, 0x42], unicast[16..18]);
+        assert_eq!(vec![0xFF; 6], unicast[18..24]);
+        assert_eq!(mac.octets().repeat(16), unicast[24..120]);
+        assert_eq!(password.bytes(), &unicast[120..]);
+    }
+
     #[test]
     fn test_ethernet_packet_with_vlan() {
         let maybe_mac = "01:23:45:67:89:AB";
         let mac = maybe_mac.parse::<MacAddr>().unwrap();
         let dest_mac = MacAddr::broadcast();
-        let vlan_id = Some(0x0101);
+        let vlan = Vlan::from(0x0101);
 
-        let interface = datalink::interfaces()
-            .into_iter()
-            .find(|iface| iface.mac.is_some())
-            .expect("cannot find an interface with a MAC address for testing");
+        let interface = test_interface();
 
         let payload_size = SIZE_VLAN_TAG + SIZE_VLAN_ETHERTYPE + SIZE_WOL_PAYLOAD;
         let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
 
-        let (buffer, _interface) = build_wol_packet(maybe_mac, &interface.name, vlan_id)
+        let buffer = build_wol_packet(mac, Destination::Broadcast, &interface, Some(&vlan), None)
             .expect("failed to build test packet");
 
         assert_eq!(packet_size, buffer.len());
@@ -241,4 +966,191 @@ mod tests {
         // WOL target MAC x 16
         assert_eq!(mac.octets().repeat(16), buffer[24..]);
     }
+
+    #[test]
+    fn test_secureon_password_formats() {
+        let expected = vec![0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0xF6];
+        for password in ["a1b2c3d4e5f6", "A1:B2:C3:D4:E5:F6", "a1-b2-c3-d4-e5-f6"] {
+            let parsed = password.parse::<SecureOnPassword>().unwrap();
+            assert_eq!(expected, parsed.bytes());
+        }
+
+        let parsed = "a1:b2:c3:d4".parse::<SecureOnPassword>().unwrap();
+        assert_eq!(vec![0xA1, 0xB2, 0xC3, 0xD4], parsed.bytes());
+
+        for password in [
+            "",
+            "a1b2c3",
+            "a1b2c3d4e5",
+            "a1:b2:c3",
+            "a1b:2c3:d4",
+            "zzzzzzzz",
+            "a1:b2-c3:d4",
+            "a1-b2-c3-d4-e5:f6",
+        ] {
+            assert!(
+                matches!(
+                    password.parse::<SecureOnPassword>(),
+                    Err(WOLError::InvalidPassword(_))
+                ),
+                "Expected InvalidPassword error for {:?}",
+                password
+            );
+        }
+    }
+
+    #[test]
+    fn test_ethernet_packet_with_password() {
+        let maybe_mac = "01:23:45:67:89:AB";
+        let mac = maybe_mac.parse::<MacAddr>().unwrap();
+        let password = "a1:b2:c3:d4:e5:f6".parse::<SecureOnPassword>().unwrap();
+
+        let interface = test_interface();
+
+        let payload_size = SIZE_WOL_PAYLOAD + SIZE_SECUREON_PASSWORD;
+        let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
+
+        let buffer = build_wol_packet(
+            mac,
+            Destination::Broadcast,
+            &interface,
+            None,
+            Some(&password),
+        )
+        .expect("failed to build test packet");
+
+        assert_eq!(packet_size, buffer.len());
+
+        // WOL Ethertype
+        assert_eq!(vec![0x08, 0x42], buffer[12..14]);
+        // WOL packet prefix
+        assert_eq!(vec![0xFF; 6], buffer[14..20]);
+        // WOL target MAC x 16
+        assert_eq!(mac.octets().repeat(16), buffer[20..116]);
+        // SecureOn password
+        assert_eq!(password.bytes(), &buffer[116..]);
+    }
+
+    #[test]
+    fn test_ethernet_packet_with_vlan_priority() {
+        let maybe_mac = "01:23:45:67:89:AB";
+        let mac = maybe_mac.parse::<MacAddr>().unwrap();
+        let vlan = Vlan {
+            tag: VlanTag {
+                id: 0x0123,
+                priority: 5,
+                dei: true,
+            },
+            outer: None,
+        };
+
+        let interface = test_interface();
+
+        let payload_size = SIZE_VLAN_TAG + SIZE_VLAN_ETHERTYPE + SIZE_WOL_PAYLOAD;
+        let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
+
+        let buffer = build_wol_packet(mac, Destination::Broadcast, &interface, Some(&vlan), None)
+            .expect("failed to build test packet");
+
+        assert_eq!(packet_size, buffer.len());
+
+        // VLAN Ethertype
+        assert_eq!(vec![0x81, 0x00], buffer[12..14]);
+        // VLAN Tag: PCP 5 (101), DEI 1, VID 0x123
+        assert_eq!(vec![0xB1, 0x23], buffer[14..16]);
+        // WOL Ethertype
+        assert_eq!(vec![0x08, 0x42], buffer[16..18]);
+        // WOL packet prefix
+        assert_eq!(vec![0xFF; 6], buffer[18..24]);
+        // WOL target MAC x 16
+        assert_eq!(mac.octets().repeat(16), buffer[24..]);
+    }
+
+    #[test]
+    fn test_ethernet_packet_with_qinq() {
+        let maybe_mac = "01:23:45:67:89:AB";
+        let mac = maybe_mac.parse::<MacAddr>().unwrap();
+        let dest_mac = MacAddr::broadcast();
+        let vlan = Vlan {
+            tag: VlanTag {
+                id: 0x0101,
+                priority: 0,
+                dei: false,
+            },
+            outer: Some(VlanTag {
+                id: 0x0020,
+                priority: 3,
+                dei: false,
+            }),
+        };
+
+        let interface = test_interface();
+
+        let payload_size = 2 * (SIZE_VLAN_TAG + SIZE_VLAN_ETHERTYPE) + SIZE_WOL_PAYLOAD;
+        let packet_size = SIZE_DST_MAC + SIZE_SRC_MAC + SIZE_ETHERTYPE + payload_size;
+
+        let buffer = build_wol_packet(mac, Destination::Broadcast, &interface, Some(&vlan), None)
+            .expect("failed to build test packet");
+
+        assert_eq!(packet_size, buffer.len());
+
+        // L2 broadcast address
+        assert_eq!(dest_mac.octets(), buffer[..6]);
+        // Source MAC
+        assert_eq!(interface.mac.unwrap().octets(), buffer[6..12]);
+        // S-tag Ethertype
+        assert_eq!(vec![0x88, 0xA8], buffer[12..14]);
+        // S-tag: PCP 3 (011), DEI 0, VID 0x020
+        assert_eq!(vec![0x60, 0x20], buffer[14..16]);
+        // C-tag Ethertype
+        assert_eq!(vec![0x81, 0x00], buffer[16..18]);
+        // C-tag
+        assert_eq!(vec![0x01, 0x01], buffer[18..20]);
+        // WOL Ethertype
+        assert_eq!(vec![0x08, 0x42], buffer[20..22]);
+        // WOL packet prefix
+        assert_eq!(vec![0xFF; 6], buffer[22..28]);
+        // WOL target MAC x 16
+        assert_eq!(mac.octets().repeat(16), buffer[28..]);
+    }
+
+    #[test]
+    fn test_vlan_config() {
+        let vlan: Vlan = serde_yaml_ng::from_str("100").unwrap();
+        assert_eq!(vlan, Vlan::from(100));
+
+        let vlan: Vlan = serde_yaml_ng::from_str(
+            r#"
+            id: 100
+            priority: 6
+            dei: true
+            outer:
+              id: 20
+              priority: 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            vlan,
+            Vlan {
+                tag: VlanTag {
+                    id: 100,
+                    priority: 6,
+                    dei: true
+                },
+                outer: Some(VlanTag {
+                    id: 20,
+                    priority: 1,
+                    dei: false
+                }),
+            }
+        );
+
+        // VLAN ID out of range
+        assert!(serde_yaml_ng::from_str::<Vlan>("4096").is_err());
+        // Priority out of range
+        assert!(serde_yaml_ng::from_str::<Vlan>("{id: 1, priority: 8}").is_err());
+        // Outer tag out of range
+        assert!(serde_yaml_ng::from_str::<Vlan>("{id: 1, outer: {id: 5000}}").is_err());
+    }
 }